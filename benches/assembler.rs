@@ -0,0 +1,46 @@
+//! Benchmark for the assembler, assembling generated programs full of label references at sizes up
+//! to the 64 KiB limit, run with `cargo bench --bench assembler`
+//!
+//! Each block of the generated program is 10 bytes with a label and three references to labels
+//! elsewhere in the program, so the time per block should stay flat as the program grows.
+
+extern crate rusty_jello;
+
+use rusty_jello::Assembler;
+use std::time::Instant;
+
+const BLOCKS: [usize; 5] = [500, 1000, 2000, 4000, 6500];
+
+fn generate_program(blocks: usize) -> String {
+  let mut result: String = String::new();
+  for block in 0..blocks {
+    result += format!(":BLOCK_{}\n", block).as_str();
+    result += format!("  PUSHI :BLOCK_{}\n", (block * 7919) % blocks).as_str();
+    result += format!("  PUSHI :BLOCK_{}+1\n", (block * 104729) % blocks).as_str();
+    result += "  DROP\n";
+    result += format!("  JMPI :BLOCK_{}\n", (block + 1) % blocks).as_str();
+  }
+  return result;
+}
+
+fn main() {
+  println!("{:>8} {:>8} {:>12} {:>18}", "blocks", "bytes", "seconds", "microseconds/block");
+  for blocks in BLOCKS.iter() {
+    let program: String = generate_program(*blocks);
+    let mut assembler: Assembler = Assembler::new();
+    assembler.add_string(program.as_str());
+    let start: Instant = Instant::now();
+    let bytes: usize = match assembler.assemble() {
+      Ok(bytecode) => bytecode.len(),
+      Err(diagnostics) => panic!("generated program failed to assemble, {}", diagnostics[0]),
+    };
+    let seconds: f64 = start.elapsed().as_secs_f64();
+    println!(
+      "{:>8} {:>8} {:>12.6} {:>18.3}",
+      blocks,
+      bytes,
+      seconds,
+      seconds * 1e6 / *blocks as f64
+    );
+  }
+}
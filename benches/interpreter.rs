@@ -0,0 +1,86 @@
+//! Benchmark for the interpreter, running the loop from `fib.jello` over and over until the machine
+//! halts, run with `cargo bench --bench interpreter`
+//!
+//! The loop is padded with two instructions which change nothing it depends on, once with NOOP, the
+//! first in the instruction set, and once with INPT, the last. Both rows should show about the same
+//! number of instructions per second.
+
+extern crate rusty_jello;
+
+use rusty_jello::{Assembler, Machine};
+use std::time::Instant;
+
+const REPEATS: u16 = 60000;
+
+/// Fibonacci numbers until the carry is set, repeated with R1 counting down the repeats
+const FIB: &str = "
+  LRI REPEATS
+  SR1
+:OUTER
+  PUSHI 0x0000
+  PUSHI 0x0001
+:LOOP
+  DUP
+  ROTAC
+  ADD
+  PUSH
+  TESTC
+  JMPIT :END
+  JMPI :LOOP
+:END
+  DROP
+  DROP
+  LR1
+  PUSH
+  DEC
+  SR1
+  PUSH
+  PUSHI 0x0000
+  JMPIE :DONE
+  DROP
+  DROP
+  JMPI :OUTER
+:DONE
+  HALT
+";
+
+/// Pads the fib loop with an instruction after DUP and PUSH, which are before TESTC so the padding
+/// can set the test flag without changing the result
+fn padded(program: &str, padding: &str) -> String {
+  return program
+    .replace("  DUP\n", format!("  DUP\n  {}\n", padding).as_str())
+    .replace("  ADD\n  PUSH\n", format!("  ADD\n  PUSH\n  {}\n", padding).as_str());
+}
+
+fn run(name: &str, program: &str) {
+  let mut assembler: Assembler = Assembler::new();
+  assembler.add_string(program.replace("REPEATS", format!("{}", REPEATS).as_str()).as_str());
+  let bytecode: Vec<u8> = match assembler.assemble() {
+    Ok(bytecode) => bytecode,
+    Err(diagnostics) => panic!("benchmark program failed to assemble, {}", diagnostics[0]),
+  };
+
+  let mut machine: Machine = Machine::new();
+  machine.memory[..bytecode.len()].copy_from_slice(&bytecode);
+  let start: Instant = Instant::now();
+  let mut steps: u64 = 0;
+  while !machine.flags.halt {
+    machine.step();
+    steps += 1;
+  }
+  let seconds: f64 = start.elapsed().as_secs_f64();
+  println!(
+    "{:>14} {:>12} {:>12.6} {:>18.2}",
+    name,
+    steps,
+    seconds,
+    steps as f64 / seconds / 1e6
+  );
+}
+
+fn main() {
+  println!("{:>14} {:>12} {:>12} {:>18}", "program", "instructions", "seconds", "million/second");
+  run("fib", FIB);
+  run("fib + NOOP", padded(FIB, "NOOP").as_str());
+  run("fib + INPT", padded(FIB, "INPT").as_str());
+}
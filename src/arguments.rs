@@ -22,12 +22,20 @@ pub struct Args {
 /// Gets an argument by name, if it exists
 impl Args {
   pub fn get_arg(&self, name: &str) -> Option<&Arg> {
+    return self.args.iter().find(|arg| arg.name == name);
+  }
+}
+
+/// Gets every argument with a name, for options which can be given more than once
+impl Args {
+  pub fn get_args(&self, name: &str) -> Vec<&Arg> {
+    let mut results: Vec<&Arg> = Vec::new();
     for arg in &self.args {
-      if name.to_string() == arg.name {
-        return Some(&arg);
+      if name == arg.name {
+        results.push(arg);
       }
     }
-    return None;
+    return results;
   }
 }
 
@@ -35,7 +43,7 @@ impl Args {
 impl Args {
   pub fn has_arg(&self, name: &str) -> bool {
     for arg in &self.args {
-      if name.to_string() == arg.name {
+      if name == arg.name {
         return true;
       }
     }
@@ -48,7 +56,7 @@ impl Args {
   pub fn count_arg(&self, name: &str) -> usize {
     let mut count: usize = 0;
     for arg in &self.args {
-      if name.to_string() == arg.name {
+      if name == arg.name {
         count += 1;
       }
     }
@@ -61,7 +69,7 @@ impl Args {
   pub fn new(args: Vec<String>, flags: Vec<String>) -> Args {
     let mut results: Vec<Arg> = Vec::new();
     let mut pos: usize = 1;
-    if args.len() > 0 {
+    if !args.is_empty() {
       while pos < args.len() {
         let item: &String = &args[pos];
         pos += 1;
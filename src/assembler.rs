@@ -1,9 +1,16 @@
 //! Module for compiling lines of code into byte code for the Rusty Jello machine
 
+use diagnostic;
+use diagnostic::{Diagnostic, DiagnosticKind, Note};
+use expression;
+use expression::Expression;
 use instructions;
-use std::error::Error;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use instructions::Instruction;
+use object_file::{LineEntry, ObjectFile, Segment, Symbol};
 
 #[derive(Clone)]
 enum ByteType {
@@ -11,15 +18,17 @@ enum ByteType {
   Label,
   LabelPadding,
   OpCode,
+  Expression,
 }
 
 impl fmt::Display for ByteType {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    match self {
-      &ByteType::Data => write!(f, "Data"),
-      &ByteType::Label => write!(f, "Label"),
-      &ByteType::LabelPadding => write!(f, "LabelPadding"),
-      &ByteType::OpCode => write!(f, "OpCode"),
+    match *self {
+      ByteType::Data => write!(f, "Data"),
+      ByteType::Label => write!(f, "Label"),
+      ByteType::LabelPadding => write!(f, "LabelPadding"),
+      ByteType::OpCode => write!(f, "OpCode"),
+      ByteType::Expression => write!(f, "Expression"),
     }
   }
 }
@@ -30,6 +39,9 @@ struct Byte {
   byte_value: u8,
   byte_label: String,
   byte_attached_labels: Vec<String>,
+  byte_line: usize,
+  byte_span: (usize, usize),
+  byte_expression: Option<Expression>,
 }
 
 impl Byte {
@@ -39,6 +51,9 @@ impl Byte {
       byte_value: val,
       byte_label: "".to_string(),
       byte_attached_labels: vec![],
+      byte_line: 0,
+      byte_span: (0, 0),
+      byte_expression: None,
     };
   }
 }
@@ -74,134 +89,1368 @@ impl fmt::Debug for Label {
   }
 }
 
-#[derive(Debug)]
-pub struct AssembleError {
-  error_type: String,
-  error_description: String,
+/// An item of a line along with the column it starts at
+#[derive(Clone)]
+struct LineItem {
+  text: String,
+  column: usize,
 }
 
-impl fmt::Display for AssembleError {
-  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "{}: {}", self.error_type, self.error_description)
+impl LineItem {
+  fn span(&self) -> (usize, usize) {
+    (self.column, self.column + self.text.chars().count())
   }
 }
 
-impl Error for AssembleError {
-  fn description(&self) -> &str {
-    "Compiler Error"
-  }
+/// A named value defined with .EQU or .CONST, constants defined by an expression are kept as one
+/// and evaluated where they are used
+#[derive(Clone)]
+struct Constant {
+  line: usize,
+  value: Vec<Byte>,
+  expression: Option<Expression>,
+  string: bool,
+}
+
+/// A line of source along with the file (an index into the assembler's files) and line number it
+/// was read from
+#[derive(Clone)]
+struct SourceLine {
+  file: usize,
+  line: usize,
+  text: String,
 }
 
+/// A macro defined with .MACRO, the body is kept as source lines
+#[derive(Clone)]
+struct Macro {
+  definition: SourceLine,
+  parameters: Vec<String>,
+  body: Vec<SourceLine>,
+}
+
+/// Where a line of the expanded program came from, the expansions are the macro invocations it
+/// was expanded from, innermost first
+#[derive(Clone)]
+struct LineOrigin {
+  file: usize,
+  line: usize,
+  expansions: Vec<Note>,
+}
+
+/// A label named by an .EXPORT or .IMPORT directive, along with where it was named
+#[derive(Clone)]
+struct Linkage {
+  name: String,
+  line: usize,
+  span: (usize, usize),
+}
+
+/// How a value depends on where the object is placed, found by moving the labels it uses
+enum Dependency {
+  Absolute,
+  Relocatable,
+  Import(String),
+  Fixed,
+}
+
+/// How deeply macros can invoke other macros before expansion gives up, which catches macros that
+/// invoke themselves
+const MAX_MACRO_DEPTH: usize = 16;
+
+/// How many macro expansions a program can have in total, which catches macros that invoke others
+/// so many times over that expanding them would never finish. Expansions almost always assemble
+/// to at least 1 byte, so a program with more of them wouldn't fit in memory anyway
+const MAX_MACRO_EXPANSIONS: usize = 65536;
+
 pub struct Assembler {
-  program: String,
+  inputs: Vec<(Option<PathBuf>, String)>,
+  search_paths: Vec<PathBuf>,
+  diagnostics: Vec<Diagnostic>,
+  constants: HashMap<String, Constant>,
+  macros: HashMap<String, Macro>,
+  files: Vec<Option<PathBuf>>,
+  source: Vec<SourceLine>,
+  expanded: String,
+  origins: Vec<LineOrigin>,
+  expansion_count: usize,
+  source_labels: HashSet<String>,
+  exports: Vec<Linkage>,
+  imports: Vec<Linkage>,
+  relocations: Vec<u16>,
+  import_references: Vec<Symbol>,
+  fixed: Vec<u16>,
+  scopes: Vec<String>,
+  anonymous_labels: HashMap<char, Vec<usize>>,
+  macro_labels: HashSet<String>,
+  symbols: HashMap<String, u16>,
+  listing: String,
+}
+
+impl Default for Assembler {
+  fn default() -> Assembler {
+    return Assembler::new();
+  }
 }
 
 impl Assembler {
   pub fn new() -> Assembler {
     return Assembler {
-      program: "".to_string(),
+      inputs: Vec::new(),
+      search_paths: Vec::new(),
+      diagnostics: Vec::new(),
+      constants: HashMap::new(),
+      macros: HashMap::new(),
+      files: Vec::new(),
+      source: Vec::new(),
+      expanded: "".to_string(),
+      origins: Vec::new(),
+      expansion_count: 0,
+      source_labels: HashSet::new(),
+      exports: Vec::new(),
+      imports: Vec::new(),
+      relocations: Vec::new(),
+      import_references: Vec::new(),
+      fixed: Vec::new(),
+      scopes: Vec::new(),
+      anonymous_labels: HashMap::new(),
+      macro_labels: HashSet::new(),
+      symbols: HashMap::new(),
+      listing: String::new(),
     };
   }
 }
 
 impl Assembler {
   pub fn add_string(&mut self, new_lines: &str) {
-    self.program.push_str(new_lines);
+    match self.inputs.last_mut() {
+      Some(&mut (None, ref mut program)) => program.push_str(new_lines),
+      _ => self.inputs.push((None, new_lines.to_string())),
+    }
+  }
+}
+
+/// Adds source read from a file, .INCLUDE paths in it are resolved relative to the file and
+/// diagnostics name it
+impl Assembler {
+  pub fn add_file_string(&mut self, path: &Path, new_lines: &str) {
+    self.inputs.push((Some(path.to_path_buf()), new_lines.to_string()));
+  }
+}
+
+/// Adds a directory to search for included files which aren't next to the file including them
+impl Assembler {
+  pub fn add_search_path(&mut self, path: &Path) {
+    self.search_paths.push(path.to_path_buf());
+  }
+}
+
+/// All errors and warnings found by the last call to assemble
+impl Assembler {
+  pub fn diagnostics(&self) -> &Vec<Diagnostic> {
+    &self.diagnostics
+  }
+}
+
+/// The listing of the program from the last successful call to assemble, showing the address and
+/// bytes each line was assembled to, followed by the labels
+impl Assembler {
+  pub fn listing(&self) -> &str {
+    &self.listing
+  }
+}
+
+impl Assembler {
+  pub fn assemble(&mut self) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let (_, bytecode) = self.assemble_program()?;
+    return Ok(bytecode);
+  }
+}
+
+/// Assembles the program into an object file, including its symbol and line tables
+impl Assembler {
+  pub fn assemble_object(&mut self) -> Result<ObjectFile, Vec<Diagnostic>> {
+    let (partially_compiled_bytecode, bytecode) = self.assemble_program()?;
+    let mut result: ObjectFile = ObjectFile::new();
+    let mut last_line: Option<(usize, usize)> = None;
+    result.files = (0..self.files.len()).map(|file| self.file_name(file).unwrap_or_default()).collect();
+    for (address, byte) in partially_compiled_bytecode.iter().enumerate() {
+      let symbols = byte
+        .byte_attached_labels
+        .iter()
+        .filter(|label| !Assembler::is_anonymous_definition(label) && !self.macro_labels.contains(label.as_str()));
+      for label in symbols {
+        result.symbols.push(Symbol {
+          name: label.to_string(),
+          address: address as u16,
+        });
+      }
+      if let Some(origin) = self.origins.get(byte.byte_line.wrapping_sub(1)) {
+        if last_line != Some((origin.file, origin.line)) {
+          result.lines.push(LineEntry {
+            address: address as u16,
+            file: origin.file as u16,
+            line: origin.line as u32,
+          });
+          last_line = Some((origin.file, origin.line));
+        }
+      }
+    }
+    result.segments.push(Segment {
+      load_address: 0,
+      bytes: bytecode,
+    });
+    for export in self.exports.iter() {
+      if let Some(&address) = self.symbols.get(&export.name) {
+        result.exports.push(Symbol {
+          name: export.name.clone(),
+          address,
+        });
+      }
+    }
+    result.relocations = self.relocations.clone();
+    result.imports = self.import_references.clone();
+    result.fixed = self.fixed.clone();
+    return Ok(result);
+  }
+}
+
+/// Runs every check and assembly pass, collecting all diagnostics rather than stopping at the
+/// first error, returns the partially compiled byte code along with the final byte code
+impl Assembler {
+  fn assemble_program(&mut self) -> Result<(Vec<Byte>, Vec<u8>), Vec<Diagnostic>> {
+    self.diagnostics.clear();
+    self.listing.clear();
+    let mut macro_diagnostics: Vec<Diagnostic> = self.include_files();
+    macro_diagnostics.extend(self.expand_macros());
+    self.collect_scopes();
+    self.collect_constants();
+    self.collect_linkage();
+    self.check_labels();
+    let partially_compiled_bytecode: Vec<Byte> = self.assemble_program_to_bytecode();
+    self.build_symbol_table(&partially_compiled_bytecode);
+    self.check_label_references(&partially_compiled_bytecode);
+    self.check_linkage();
+    let mut bytecode: Vec<u8> = Vec::new();
+    if !self.diagnostics.iter().any(|diagnostic| diagnostic.is_error()) && macro_diagnostics.is_empty() {
+      bytecode = self.resolve_labels(&partially_compiled_bytecode);
+      self.listing = self.build_listing(&partially_compiled_bytecode, &bytecode);
+    }
+
+    let diagnostics: Vec<Diagnostic> = self.diagnostics.drain(..).collect();
+    for diagnostic in diagnostics {
+      match self.origins.get(diagnostic.line.wrapping_sub(1)) {
+        Some(origin) => {
+          let located: Diagnostic = self.at_origin(diagnostic, origin);
+          self.diagnostics.push(located);
+        }
+        None => self.diagnostics.push(diagnostic),
+      }
+    }
+    self.diagnostics.extend(macro_diagnostics);
+
+    let file_names: Vec<Option<String>> = (0..self.files.len()).map(|file| self.file_name(file)).collect();
+    self.diagnostics.sort_by_key(|diagnostic| {
+      (file_names.iter().position(|name| *name == diagnostic.file), diagnostic.line)
+    });
+    if self.diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+      return Err(self.diagnostics.clone());
+    }
+    return Ok((partially_compiled_bytecode, bytecode));
+  }
+}
+
+/// Lays out each line of the program (after macro expansion) with the address and bytes it was
+/// assembled to, lines expanded from a macro are marked with +, followed by a table of the labels
+impl Assembler {
+  fn build_listing(&self, partially_compiled_bytecode: &[Byte], bytecode: &[u8]) -> String {
+    let mut runs: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+    let mut address: usize = 0;
+    while address < partially_compiled_bytecode.len() {
+      let line: usize = partially_compiled_bytecode[address].byte_line;
+      let start: usize = address;
+      while address < partially_compiled_bytecode.len() && partially_compiled_bytecode[address].byte_line == line {
+        address += 1;
+      }
+      if line != 0 {
+        runs.entry(line).or_default().push((start, address));
+      }
+    }
+
+    let mut result: String = format!("{:<6}{:<24} {:>5}  {}\n", "Addr", "Bytes", "Line", "Source");
+    let mut current_file: Option<usize> = None;
+    for (index, line) in self.expanded.lines().enumerate() {
+      let origin: &LineOrigin = &self.origins[index];
+      if current_file != Some(origin.file) {
+        let name: String = self.file_name(origin.file).unwrap_or_else(|| "<input>".to_string());
+        result += format!("\nFile: {}\n", name).as_str();
+        current_file = Some(origin.file);
+      }
+      let marker: char = if origin.expansions.is_empty() { ' ' } else { '+' };
+      let source: String = format!("{:>5}{} {}", origin.line, marker, line);
+      let ranges: Vec<(usize, usize)> = runs.get(&(index + 1)).cloned().unwrap_or_default();
+      if ranges.is_empty() {
+        result += format!("{:<6}{:<24} {}\n", "", "", source).as_str();
+        continue;
+      }
+      let mut first: bool = true;
+      for (start, end) in ranges {
+        for chunk_start in (start..end).step_by(8) {
+          let chunk_end: usize = end.min(chunk_start + 8);
+          let bytes: Vec<String> = bytecode[chunk_start..chunk_end].iter().map(|byte| format!("{:02x}", byte)).collect();
+          let row: String = format!("{:04x}  {:<24}", chunk_start, bytes.join(" "));
+          if first {
+            result += format!("{} {}\n", row, source).as_str();
+            first = false;
+          } else {
+            result += format!("{}\n", row.trim_end()).as_str();
+          }
+        }
+      }
+    }
+
+    let mut symbols: Vec<(usize, String)> = Vec::new();
+    for (address, byte) in partially_compiled_bytecode.iter().enumerate() {
+      for label in byte.byte_attached_labels.iter().filter(|label| !Assembler::is_anonymous_definition(label)) {
+        symbols.push((address, label.to_string()));
+      }
+    }
+    symbols.sort();
+    result += "\nSymbols:\n";
+    for (address, name) in symbols {
+      let exported: &str = if self.exports.iter().any(|export| export.name == name) { " (exported)" } else { "" };
+      result += format!("  {:04x}  :{}{}\n", address, name, exported).as_str();
+    }
+    for import in self.imports.iter() {
+      result += format!("  ----  :{} (imported)\n", import.name).as_str();
+    }
+    return result;
+  }
+}
+
+/// Records the address of every label once the first pass has placed them, each label is attached
+/// to the byte at its address
+impl Assembler {
+  fn build_symbol_table(&mut self, partially_compiled_bytecode: &[Byte]) {
+    self.symbols.clear();
+    for (address, byte) in partially_compiled_bytecode.iter().enumerate() {
+      for label in byte.byte_attached_labels.iter() {
+        self.symbols.entry(label.to_string()).or_insert(address as u16);
+      }
+    }
+  }
+}
+
+/// Names of every label in the symbol table in address order, so "did you mean" hints don't depend
+/// on the order of the hash map
+impl Assembler {
+  fn defined_labels(&self) -> Vec<&str> {
+    let mut result: Vec<(&str, u16)> = self.symbols.iter().map(|(name, &address)| (name.as_str(), address)).collect();
+    result.sort_by_key(|&(name, address)| (address, name));
+    return result.into_iter().map(|(name, _)| name).collect();
   }
 }
 
+/// Fills in label addresses and evaluates expressions, expressions which can't be evaluated or
+/// don't fit their operand are reported and left as zeroes, also records which values need to be
+/// adjusted when the object is linked
 impl Assembler {
-  pub fn assemble(&mut self) -> Result<Vec<u8>, String> {
+  fn resolve_labels(&mut self, partially_compiled_bytecode: &[Byte]) -> Vec<u8> {
     let mut result: Vec<u8> = Vec::new();
-    self.check_labels()?;
-    let partially_compiled_bytecode = self.assemble_program_to_bytecode()?;
+    self.relocations.clear();
+    self.import_references.clear();
+    self.fixed.clear();
     for byte in partially_compiled_bytecode.iter() {
       match byte.byte_type {
         ByteType::Data => result.push(byte.byte_value),
         ByteType::Label => {
-          let mut address: u16 = 0;
-          for possible_label_byte in partially_compiled_bytecode.iter() {
-            if possible_label_byte
-              .byte_attached_labels
-              .contains(&byte.byte_label)
-            {
-              result.push((address & 0xff) as u8);
-              result.push((address >> 8) as u8);
+          if self.is_imported(byte.byte_label.as_str()) {
+            self.import_references.push(Symbol {
+              name: byte.byte_label.clone(),
+              address: result.len() as u16,
+            });
+            result.push(0);
+            result.push(0);
+          } else if let Some(&address) = self.symbols.get(&byte.byte_label) {
+            self.relocations.push(result.len() as u16);
+            result.push((address & 0xff) as u8);
+            result.push((address >> 8) as u8);
+          }
+        }
+        ByteType::OpCode => result.push(byte.byte_value),
+        ByteType::LabelPadding => {}
+        ByteType::Expression => {
+          let width: usize = byte.byte_value as usize;
+          let evaluated: Result<Vec<u8>, String> = match byte.byte_expression {
+            Some(ref expression) => self
+              .record_dependency(expression, result.len() as u16, width)
+              .and_then(|_| expression.evaluate(&|name: &str| self.link_address(name, 0, "", 0)))
+              .and_then(|value| expression::encode(value, width)),
+            None => Err("missing expression".to_string()),
+          };
+          match evaluated {
+            Ok(bytes) => result.extend(bytes),
+            Err(message) => {
+              let source_line: &str = self.expanded.lines().nth(byte.byte_line - 1).unwrap_or("");
+              self.diagnostics.push(Diagnostic::error(
+                DiagnosticKind::ValueError,
+                byte.byte_line,
+                byte.byte_span,
+                message,
+                source_line,
+              ));
+              result.extend(vec![0; width]);
+            }
+          }
+        }
+      }
+    }
+    return result;
+  }
+}
+
+/// Finds the address of a label while working out what a value depends on, local labels are
+/// moved by one amount and a single imported label by another, other imported labels are 0
+impl Assembler {
+  fn link_address(&self, name: &str, local_offset: i64, import: &str, import_offset: i64) -> Option<i64> {
+    if self.is_imported(name) {
+      return Some(if name == import { import_offset } else { 0 });
+    }
+    return self.symbols.get(name).map(|&address| address as i64 + local_offset);
+  }
+}
+
+/// Records the relocation or import an expression needs, values which can only be an address plus
+/// a constant can be adjusted by the linker and anything else depending on where the object is
+/// placed pins it to address 0
+impl Assembler {
+  fn record_dependency(&mut self, expression: &Expression, address: u16, width: usize) -> Result<(), String> {
+    let mut dependencies: Vec<Dependency> = Vec::new();
+    let mut names: Vec<String> = expression.labels().into_iter().filter(|name| self.is_imported(name)).collect();
+    names.sort();
+    names.dedup();
+    match self.dependency_on(expression, "") {
+      Dependency::Absolute => {}
+      dependency => dependencies.push(dependency),
+    }
+    for name in names.iter() {
+      match self.dependency_on(expression, name) {
+        Dependency::Absolute => {}
+        Dependency::Relocatable => dependencies.push(Dependency::Import(name.clone())),
+        _ => {
+          return Err(format!(
+            "expression using imported label \"{}\" can't be linked, only :{} plus or minus a constant can be",
+            name,
+            name
+          ))
+        }
+      }
+    }
+
+    match dependencies.pop() {
+      None => {}
+      Some(Dependency::Relocatable) if dependencies.is_empty() && width == 2 => self.relocations.push(address),
+      Some(Dependency::Import(name)) if dependencies.is_empty() && width == 2 => {
+        self.import_references.push(Symbol { name, address })
+      }
+      Some(Dependency::Import(name)) => {
+        return Err(format!(
+          "expression using imported label \"{}\" can't be linked, only :{} plus or minus a constant can be",
+          name,
+          name
+        ))
+      }
+      Some(..) => self.fixed.push(address),
+    }
+    return Ok(());
+  }
+}
+
+/// Moves either the local labels or one imported label by two different amounts, an expression
+/// which moves by the same amount is relocatable and one which doesn't move at all is absolute
+impl Assembler {
+  fn dependency_on(&self, expression: &Expression, import: &str) -> Dependency {
+    let moved = |offset: i64| {
+      let (local_offset, import_offset) = if import.is_empty() { (offset, 0) } else { (0, offset) };
+      expression.evaluate(&|name: &str| self.link_address(name, local_offset, import, import_offset))
+    };
+    let base: i64 = match moved(0) {
+      Ok(value) => value,
+      Err(..) => return Dependency::Fixed,
+    };
+    let mut absolute: bool = true;
+    let mut relocatable: bool = true;
+    for offset in [0x1234, 0x0101].iter() {
+      match moved(*offset) {
+        Ok(value) => {
+          absolute = absolute && value == base;
+          relocatable = relocatable && value - base == *offset;
+        }
+        Err(..) => return Dependency::Fixed,
+      }
+    }
+    if absolute {
+      return Dependency::Absolute;
+    }
+    if relocatable {
+      return Dependency::Relocatable;
+    }
+    return Dependency::Fixed;
+  }
+}
+
+impl Assembler {
+  fn is_imported(&self, name: &str) -> bool {
+    self.imports.iter().any(|import| import.name == name)
+  }
+}
+
+/// Collects the .EXPORT and .IMPORT directives, which name labels to share between objects
+impl Assembler {
+  fn collect_linkage(&mut self) {
+    self.exports.clear();
+    self.imports.clear();
+    let program: String = self.expanded.clone();
+    let mut line_counter: usize = 1;
+    for line in program.lines() {
+      if !Assembler::is_linkage_directive(line) {
+        line_counter += 1;
+        continue;
+      }
+      let split_line: Vec<LineItem> = Assembler::split_line(line);
+      let directive: String = split_line[0].text.to_uppercase();
+      if split_line.len() < 2 {
+        self.diagnostics.push(
+          Diagnostic::error(
+            DiagnosticKind::SyntaxError,
+            line_counter,
+            split_line[0].span(),
+            format!("{} directive expects at least 1 label", directive),
+            line,
+          ).with_hint(format!("use {} :NAME...", directive)),
+        );
+      }
+      for item in split_line.iter().skip(1) {
+        let linkage: Linkage = Linkage {
+          name: self.qualify_reference(item.text.strip_prefix(':').unwrap_or(item.text.as_str()), line_counter),
+          line: line_counter,
+          span: item.span(),
+        };
+        if directive == ".EXPORT" {
+          self.exports.push(linkage);
+        } else {
+          self.imports.push(linkage);
+        }
+      }
+      line_counter += 1;
+    }
+  }
+}
+
+/// Checks exported labels are defined here and imported ones aren't
+impl Assembler {
+  fn check_linkage(&mut self) {
+    for export in self.exports.clone() {
+      let source_line: String = self.expanded.lines().nth(export.line - 1).unwrap_or("").to_string();
+      if self.is_imported(export.name.as_str()) {
+        self.diagnostics.push(Diagnostic::error(
+          DiagnosticKind::AmbiguousInput,
+          export.line,
+          export.span,
+          format!("label \"{}\" is both imported and exported", export.name),
+          source_line.as_str(),
+        ));
+      } else if !self.symbols.contains_key(&export.name) {
+        let mut diagnostic: Diagnostic = Diagnostic::error(
+          DiagnosticKind::UndefinedLabel,
+          export.line,
+          export.span,
+          format!("exported label \"{}\" is not defined", export.name),
+          source_line.as_str(),
+        );
+        if let Some(name) = diagnostic::closest_match(export.name.as_str(), self.defined_labels().into_iter()) {
+          diagnostic = diagnostic.with_hint(format!("did you mean \":{}\"?", name));
+        }
+        self.diagnostics.push(diagnostic);
+      }
+    }
+
+    for import in self.imports.clone() {
+      if self.symbols.contains_key(&import.name) {
+        let source_line: String = self.expanded.lines().nth(import.line - 1).unwrap_or("").to_string();
+        self.diagnostics.push(
+          Diagnostic::error(
+            DiagnosticKind::AmbiguousInput,
+            import.line,
+            import.span,
+            format!("label \"{}\" is imported but also defined here", import.name),
+            source_line.as_str(),
+          ).with_hint("remove the .IMPORT to use the label defined here".to_string()),
+        );
+      }
+    }
+  }
+}
+
+impl Assembler {
+  fn is_linkage_directive(line: &str) -> bool {
+    match line.split_whitespace().next() {
+      Some(first) => {
+        let first: String = first.to_uppercase();
+        first == ".EXPORT" || first == ".IMPORT"
+      }
+      None => false,
+    }
+  }
+}
+
+/// Reads the inputs into source lines, replacing .INCLUDE directives with the lines of the file
+/// they name, each file is only included once and a file which includes itself is an error
+impl Assembler {
+  fn include_files(&mut self) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    self.files.clear();
+    self.source.clear();
+    let mut included: Vec<PathBuf> = Vec::new();
+    let inputs: Vec<(Option<PathBuf>, String)> = self.inputs.clone();
+    for (path, text) in inputs {
+      let mut including: Vec<PathBuf> = Vec::new();
+      if let Some(ref path) = path {
+        included.push(Assembler::file_identity(path));
+        including.push(Assembler::file_identity(path));
+      }
+      let file: usize = self.files.len();
+      self.files.push(path);
+      self.include_lines(file, text.as_str(), &mut including, &mut included, &mut diagnostics);
+    }
+    return diagnostics;
+  }
+}
+
+impl Assembler {
+  fn include_lines(
+    &mut self,
+    file: usize,
+    text: &str,
+    including: &mut Vec<PathBuf>,
+    included: &mut Vec<PathBuf>,
+    diagnostics: &mut Vec<Diagnostic>,
+  ) {
+    let mut line_counter: usize = 1;
+    for line in text.lines() {
+      let split_line: Vec<LineItem> = Assembler::split_line(line);
+      let is_include: bool = split_line.first().is_some_and(|item| item.text.to_uppercase() == ".INCLUDE");
+      if !is_include {
+        self.source.push(SourceLine {
+          file,
+          line: line_counter,
+          text: line.to_string(),
+        });
+        line_counter += 1;
+        continue;
+      }
+
+      let origin: LineOrigin = LineOrigin {
+        file,
+        line: line_counter,
+        expansions: vec![],
+      };
+      match self.resolve_include(file, &split_line, line, line_counter) {
+        Ok(path) => {
+          let identity: PathBuf = Assembler::file_identity(&path);
+          if including.contains(&identity) {
+            let mut chain: Vec<String> = including.iter().map(|path| path.display().to_string()).collect();
+            chain.push(identity.display().to_string());
+            let diagnostic: Diagnostic = Diagnostic::error(
+              DiagnosticKind::IncludeError,
+              line_counter,
+              split_line[1].span(),
+              format!("\"{}\" includes itself", path.display()),
+              line,
+            ).with_hint(format!("include chain: {}", chain.join(" -> ")));
+            diagnostics.push(self.at_origin(diagnostic, &origin));
+          } else if !included.contains(&identity) {
+            match fs::read_to_string(&path) {
+              Ok(contents) => {
+                included.push(identity.clone());
+                including.push(identity);
+                let included_file: usize = self.files.len();
+                self.files.push(Some(path));
+                self.include_lines(included_file, contents.as_str(), including, included, diagnostics);
+                including.pop();
+              }
+              Err(err) => {
+                let diagnostic: Diagnostic = Diagnostic::error(
+                  DiagnosticKind::IncludeError,
+                  line_counter,
+                  split_line[1].span(),
+                  format!("could not read \"{}\", {}", path.display(), err),
+                  line,
+                );
+                diagnostics.push(self.at_origin(diagnostic, &origin));
+              }
+            }
+          }
+        }
+        Err(diagnostic) => diagnostics.push(self.at_origin(*diagnostic, &origin)),
+      }
+      line_counter += 1;
+    }
+  }
+}
+
+/// Finds the file an .INCLUDE directive names, looking next to the including file first and then
+/// in each of the search paths
+impl Assembler {
+  fn resolve_include(
+    &self,
+    file: usize,
+    split_line: &[LineItem],
+    line: &str,
+    line_number: usize,
+  ) -> Result<PathBuf, Box<Diagnostic>> {
+    if split_line.len() != 2 {
+      return Err(Box::new(
+        Diagnostic::error(
+          DiagnosticKind::SyntaxError,
+          line_number,
+          (split_line[0].column, line.trim_end().chars().count()),
+          format!("include directive expects 1 argument but got {}", split_line.len() - 1),
+          line,
+        ).with_hint("use .INCLUDE \"PATH\"".to_string()),
+      ));
+    }
+    let argument: &str = split_line[1].text.as_str();
+    if argument.len() < 2 || !argument.starts_with('"') || !argument.ends_with('"') {
+      return Err(Box::new(
+        Diagnostic::error(
+          DiagnosticKind::SyntaxError,
+          line_number,
+          split_line[1].span(),
+          format!("include path {} is not quoted", argument),
+          line,
+        ).with_hint(format!("use .INCLUDE \"{}\"", argument)),
+      ));
+    }
+    let name: &str = &argument[1..argument.len() - 1];
+
+    let mut directories: Vec<PathBuf> = Vec::new();
+    match self.files[file].as_ref().and_then(|path| path.parent()) {
+      Some(directory) => directories.push(directory.to_path_buf()),
+      None => directories.push(PathBuf::new()),
+    }
+    directories.extend(self.search_paths.iter().cloned());
+    for directory in directories.iter() {
+      let candidate: PathBuf = directory.join(name);
+      if candidate.is_file() {
+        return Ok(candidate);
+      }
+    }
+
+    let searched: Vec<String> = directories
+      .iter()
+      .map(|directory| match directory.to_str() {
+        Some("") => ".".to_string(),
+        _ => directory.display().to_string(),
+      })
+      .collect();
+    return Err(Box::new(
+      Diagnostic::error(
+        DiagnosticKind::IncludeError,
+        line_number,
+        split_line[1].span(),
+        format!("could not find included file \"{}\"", name),
+        line,
+      ).with_hint(format!("searched {}, add directories to search with -I", searched.join(", "))),
+    ));
+  }
+}
+
+impl Assembler {
+  fn file_identity(path: &Path) -> PathBuf {
+    return fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+  }
+}
+
+impl Assembler {
+  fn file_name(&self, file: usize) -> Option<String> {
+    match self.files.get(file) {
+      Some(Some(path)) => Some(path.display().to_string()),
+      _ => None,
+    }
+  }
+}
+
+/// Describes a line for hints, naming the file if it isn't the main one
+impl Assembler {
+  fn describe_location(&self, file: usize, line: usize) -> String {
+    match self.file_name(file) {
+      Some(ref name) if file != 0 => format!("line {} of {}", line, name),
+      _ => format!("line {}", line),
+    }
+  }
+}
+
+/// Describes a line of the expanded program for hints, by where it came from
+impl Assembler {
+  fn describe_line(&self, expanded_line: usize) -> String {
+    match self.origins.get(expanded_line.wrapping_sub(1)) {
+      Some(origin) => self.describe_location(origin.file, origin.line),
+      None => format!("line {}", expanded_line),
+    }
+  }
+}
+
+/// Expands macro invocations into their bodies, producing the program the other passes work on
+/// along with where each of its lines came from
+impl Assembler {
+  fn expand_macros(&mut self) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = self.collect_macros();
+    self.expanded.clear();
+    self.origins.clear();
+    self.expansion_count = 0;
+    // Labels in macro bodies are renamed for each expansion, avoiding any label written in the
+    // source
+    self.source_labels = self
+      .source
+      .iter()
+      .filter_map(|source_line| source_line.text.trim().strip_prefix(':').map(|label| label.to_string()))
+      .collect();
+
+    let source: Vec<SourceLine> = self.source.clone();
+    let mut in_macro: bool = false;
+    for source_line in source.iter() {
+      let directive: String = source_line.text.split_whitespace().next().unwrap_or("").to_uppercase();
+      if directive == ".MACRO" {
+        in_macro = true;
+      } else if directive == ".ENDM" {
+        in_macro = false;
+      } else if !in_macro {
+        let origin: LineOrigin = LineOrigin {
+          file: source_line.file,
+          line: source_line.line,
+          expansions: vec![],
+        };
+        self.expand_line(source_line.text.as_str(), origin, 0, &mut diagnostics);
+      }
+    }
+    return diagnostics;
+  }
+}
+
+/// Collects the .MACRO definitions, so macros can be invoked before they are defined
+impl Assembler {
+  fn collect_macros(&mut self) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    self.macros.clear();
+    let mut current: Option<(String, Macro)> = None;
+    let source: Vec<SourceLine> = self.source.clone();
+    for source_line in source.iter() {
+      let line: &str = source_line.text.as_str();
+      let line_number: usize = source_line.line;
+      let mut line_diagnostics: Vec<Diagnostic> = Vec::new();
+      let split_line: Vec<LineItem> = Assembler::split_line(line);
+      let directive: String = match split_line.first() {
+        Some(item) => item.text.to_uppercase(),
+        None => "".to_string(),
+      };
+
+      if directive == ".MACRO" {
+        if let Some((_, ref definition)) = current {
+          line_diagnostics.push(
+            Diagnostic::error(
+              DiagnosticKind::MacroError,
+              line_number,
+              split_line[0].span(),
+              "macro definitions can't be nested".to_string(),
+              line,
+            ).with_hint(format!(
+              "the macro on {} is missing .ENDM",
+              self.describe_location(definition.definition.file, definition.definition.line)
+            )),
+          );
+        }
+        let mut definition: Macro = Macro {
+          definition: source_line.clone(),
+          parameters: vec![],
+          body: vec![],
+        };
+        if split_line.len() < 2 {
+          line_diagnostics.push(
+            Diagnostic::error(
+              DiagnosticKind::SyntaxError,
+              line_number,
+              split_line[0].span(),
+              "macro has no name".to_string(),
+              line,
+            ).with_hint("use .MACRO NAME PARAMETER...".to_string()),
+          );
+          current = Some(("".to_string(), definition));
+        } else {
+          let name: &LineItem = &split_line[1];
+          for item in split_line.iter().skip(1) {
+            if !Assembler::is_identifier(item.text.as_str()) {
+              line_diagnostics.push(
+                Diagnostic::error(
+                  DiagnosticKind::SyntaxError,
+                  line_number,
+                  item.span(),
+                  format!("invalid macro or parameter name \"{}\"", item.text),
+                  line,
+                ).with_hint("names start with a letter or _ and contain only letters, digits and _".to_string()),
+              );
             }
-            address += 1;
           }
+          if instructions::find_inst_by_name(name.text.as_str()).is_some() {
+            line_diagnostics.push(Diagnostic::error(
+              DiagnosticKind::AmbiguousInput,
+              line_number,
+              name.span(),
+              format!("macro \"{}\" has the same name as an instruction", name.text),
+              line,
+            ));
+          } else if let Some(existing) = self.macros.get(&name.text) {
+            line_diagnostics.push(
+              Diagnostic::error(
+                DiagnosticKind::AmbiguousInput,
+                line_number,
+                name.span(),
+                format!("redefinition of macro \"{}\"", name.text),
+                line,
+              ).with_hint(format!(
+                "first defined on {}",
+                self.describe_location(existing.definition.file, existing.definition.line)
+              )),
+            );
+          }
+          definition.parameters = split_line.iter().skip(2).map(|item| item.text.clone()).collect();
+          current = Some((name.text.clone(), definition));
+        }
+      } else if directive == ".ENDM" {
+        match current.take() {
+          Some((name, definition)) => {
+            if !name.is_empty() && !self.macros.contains_key(&name) {
+              self.macros.insert(name, definition);
+            }
+          }
+          None => line_diagnostics.push(Diagnostic::error(
+            DiagnosticKind::MacroError,
+            line_number,
+            split_line[0].span(),
+            ".ENDM without a matching .MACRO".to_string(),
+            line,
+          )),
+        }
+      } else if let Some((_, ref mut definition)) = current {
+        definition.body.push(source_line.clone());
+      }
+
+      let origin: LineOrigin = LineOrigin {
+        file: source_line.file,
+        line: line_number,
+        expansions: vec![],
+      };
+      for diagnostic in line_diagnostics {
+        diagnostics.push(self.at_origin(diagnostic, &origin));
+      }
+    }
+
+    if let Some((name, definition)) = current {
+      let source_line: &str = definition.definition.text.as_str();
+      let column: usize = source_line.chars().take_while(|chr| chr.is_whitespace()).count();
+      let diagnostic: Diagnostic = Diagnostic::error(
+        DiagnosticKind::MacroError,
+        definition.definition.line,
+        (column, source_line.trim_end().chars().count()),
+        format!("macro \"{}\" is missing .ENDM", name),
+        source_line,
+      ).with_hint("end the macro body with a line containing .ENDM".to_string());
+      let origin: LineOrigin = LineOrigin {
+        file: definition.definition.file,
+        line: definition.definition.line,
+        expansions: vec![],
+      };
+      diagnostics.push(self.at_origin(diagnostic, &origin));
+    }
+    return diagnostics;
+  }
+}
+
+/// Adds a line to the expanded program, expanding it first if it invokes a macro
+impl Assembler {
+  fn expand_line(&mut self, line: &str, origin: LineOrigin, depth: usize, diagnostics: &mut Vec<Diagnostic>) {
+    let split_line: Vec<LineItem> = Assembler::split_line(line);
+    let definition: Macro = match split_line.first().and_then(|item| self.macros.get(&item.text)) {
+      Some(found) => found.clone(),
+      None => {
+        self.expanded.push_str(line);
+        self.expanded.push('\n');
+        self.origins.push(origin);
+        return;
+      }
+    };
+    let name: &LineItem = &split_line[0];
+
+    if depth >= MAX_MACRO_DEPTH {
+      let diagnostic: Diagnostic = Diagnostic::error(
+        DiagnosticKind::MacroError,
+        origin.line,
+        name.span(),
+        format!("macro expansion is more than {} levels deep", MAX_MACRO_DEPTH),
+        line,
+      ).with_hint(format!("check whether \"{}\" invokes itself", name.text));
+      let outermost: LineOrigin = LineOrigin {
+        file: origin.file,
+        line: origin.line,
+        expansions: origin.expansions.last().cloned().into_iter().collect(),
+      };
+      diagnostics.push(self.at_origin(diagnostic, &outermost));
+      return;
+    }
+    if self.expansion_count >= MAX_MACRO_EXPANSIONS {
+      // Only the first invocation over the limit is reported, the count going past the limit marks
+      // that it has been
+      if self.expansion_count == MAX_MACRO_EXPANSIONS {
+        self.expansion_count += 1;
+        let diagnostic: Diagnostic = Diagnostic::error(
+          DiagnosticKind::MacroError,
+          origin.line,
+          name.span(),
+          format!("macros are expanded more than {} times", MAX_MACRO_EXPANSIONS),
+          line,
+        ).with_hint("check for macros which invoke other macros many times over".to_string());
+        let outermost: LineOrigin = LineOrigin {
+          file: origin.file,
+          line: origin.line,
+          expansions: origin.expansions.last().cloned().into_iter().collect(),
+        };
+        diagnostics.push(self.at_origin(diagnostic, &outermost));
+      }
+      return;
+    }
+    if split_line.len() - 1 != definition.parameters.len() {
+      let location: String = self.describe_location(definition.definition.file, definition.definition.line);
+      let hint: String = if definition.parameters.is_empty() {
+        format!("{} is defined on {} without parameters", name.text, location)
+      } else {
+        format!(
+          "{} is defined on {} with parameters {}",
+          name.text,
+          location,
+          definition.parameters.join(" ")
+        )
+      };
+      let diagnostic: Diagnostic = Diagnostic::error(
+        DiagnosticKind::MacroError,
+        origin.line,
+        (name.column, split_line[split_line.len() - 1].span().1),
+        format!(
+          "macro argument count mismatch, expected {} but got {}",
+          definition.parameters.len(),
+          split_line.len() - 1
+        ),
+        line,
+      ).with_hint(hint);
+      diagnostics.push(self.at_origin(diagnostic, &origin));
+      return;
+    }
+
+    self.expansion_count += 1;
+    let mut replacements: HashMap<String, String> = HashMap::new();
+    for (parameter, argument) in definition.parameters.iter().zip(split_line.iter().skip(1)) {
+      replacements.insert(parameter.clone(), argument.text.clone());
+    }
+    for body_line in definition.body.iter() {
+      if body_line.text.trim().starts_with(':') {
+        let label: String = body_line.text.trim().chars().skip(1).collect::<String>();
+        let mut renamed: String = format!("{}__{}", label, self.expansion_count);
+        while self.source_labels.contains(&renamed) {
+          renamed.push('_');
+        }
+        replacements.insert(format!(":{}", label), format!(":{}", renamed));
+      }
+    }
+
+    let mut expansions: Vec<Note> = vec![Note {
+      message: format!("in expansion of macro \"{}\"", name.text),
+      file: self.file_name(origin.file),
+      line: origin.line,
+      span: name.span(),
+      source_line: line.to_string(),
+    }];
+    expansions.extend(origin.expansions.iter().cloned());
+    for body_line in definition.body.iter() {
+      let body_origin: LineOrigin = LineOrigin {
+        file: body_line.file,
+        line: body_line.line,
+        expansions: expansions.clone(),
+      };
+      let substituted: String = Assembler::substitute(body_line.text.as_str(), &replacements);
+      self.expand_line(substituted.as_str(), body_origin, depth + 1, diagnostics);
+    }
+  }
+}
+
+/// Replaces whole words in a line of a macro body, words starting with : are label references
+/// and other words are parameters, quoted text is left alone
+impl Assembler {
+  fn substitute(line: &str, replacements: &HashMap<String, String>) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut result: String = String::new();
+    let mut index: usize = 0;
+    while index < chars.len() {
+      let chr: char = chars[index];
+      if chr == '#' && result.trim().is_empty() {
+        result.extend(chars[index..].iter());
+        break;
+      }
+      if chr == '"' {
+        let mut escape: bool = false;
+        result.push(chr);
+        index += 1;
+        while index < chars.len() {
+          result.push(chars[index]);
+          index += 1;
+          if chars[index - 1] == '"' && !escape {
+            break;
+          }
+          escape = chars[index - 1] == '\\' && !escape;
         }
-        ByteType::OpCode => result.push(byte.byte_value),
-        ByteType::LabelPadding => {}
+        continue;
+      }
+      if chr == '\'' && index + 2 < chars.len() && chars[index + 2] == '\'' {
+        result.extend(chars[index..index + 3].iter());
+        index += 3;
+        continue;
       }
+      if chr.is_ascii_alphanumeric() || chr == '_' || chr == ':' {
+        let start: usize = index;
+        index += 1;
+        while index < chars.len()
+          && (chars[index].is_ascii_alphanumeric() || chars[index] == '_' || (chr == ':' && chars[index] == '.'))
+        {
+          index += 1;
+        }
+        let word: String = chars[start..index].iter().collect();
+        match replacements.get(&word) {
+          Some(replacement) if !chr.is_ascii_digit() => result += replacement.as_str(),
+          _ => result += word.as_str(),
+        }
+        continue;
+      }
+      result.push(chr);
+      index += 1;
     }
+    return result;
+  }
+}
 
-    return Ok(result);
+/// Moves a diagnostic from a line of the expanded program to where that line came from, noting
+/// the macro invocations it was expanded from
+impl Assembler {
+  fn at_origin(&self, mut diagnostic: Diagnostic, origin: &LineOrigin) -> Diagnostic {
+    diagnostic.file = self.file_name(origin.file);
+    diagnostic.line = origin.line;
+    for expansion in origin.expansions.iter() {
+      diagnostic = diagnostic.with_note(expansion.clone());
+    }
+    return diagnostic;
   }
 }
 
 impl Assembler {
-  fn assemble_program_to_bytecode(&mut self) -> Result<Vec<Byte>, String> {
+  fn assemble_program_to_bytecode(&mut self) -> Vec<Byte> {
     let mut result: Vec<Byte> = Vec::new();
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
     let mut line_counter: usize = 1;
-    let mut labels_from_previous: Vec<String> = Vec::new();
-    let mut data_entries: Vec<(u16, Vec<Byte>, usize)> = Vec::new();
-    for line in self.program.trim().lines() {
+    let mut labels_from_previous: Vec<Label> = Vec::new();
+    let mut data_entries: Vec<(u16, Vec<Byte>, usize, LineItem, String)> = Vec::new();
+    // Only the first line past the end of memory is reported, as every line after it is too
+    let mut past_end_reported: bool = false;
+    for line in self.expanded.lines() {
       if line.trim().is_empty() || line.trim().starts_with('#') {
         line_counter += 1;
         continue;
       }
       if line.trim().starts_with(':') {
-        labels_from_previous.push(line.trim().chars().skip(1).collect::<String>());
+        let name: String = line.trim().chars().skip(1).collect::<String>();
+        labels_from_previous.push(Label {
+          line: line_counter,
+          name: self.qualify_definition(name.as_str(), line_counter),
+        });
+        line_counter += 1;
+        continue;
+      }
+      if Assembler::is_constant_directive(line) || Assembler::is_linkage_directive(line) {
         line_counter += 1;
         continue;
       }
       if line.trim().to_uppercase().starts_with(".DATA") {
         let split_line = Assembler::split_line(line);
-        if split_line.len() == 3 {
-          if let Some(_address) = Assembler::parse_value(split_line[1].as_str()) {
-            let address: u16;
-            if _address.len() == 2 {
-              address = (_address[0].byte_value as u16) | ((_address[1].byte_value as u16) << 8);
-            } else {
-              return Err(format!("Data Address Length Error on line {}", line_counter).to_string());
+        if split_line.len() != 3 {
+          diagnostics.push(
+            Diagnostic::error(
+              DiagnosticKind::DataError,
+              line_counter,
+              (split_line[0].column, line.trim_end().chars().count()),
+              format!("data directive expects 2 arguments but got {}", split_line.len() - 1),
+              line,
+            ).with_hint("use .DATA ADDRESS VALUE".to_string()),
+          );
+          line_counter += 1;
+          continue;
+        }
+        let address: u16;
+        match self.parse_value(split_line[1].text.as_str()) {
+          Some(ref address_bytes) if address_bytes.len() == 2 => {
+            address = (address_bytes[0].byte_value as u16) | ((address_bytes[1].byte_value as u16) << 8);
+          }
+          Some(..) => {
+            diagnostics.push(
+              Diagnostic::error(
+                DiagnosticKind::DataError,
+                line_counter,
+                split_line[1].span(),
+                format!("data address \"{}\" is not 2 bytes long", split_line[1].text),
+                line,
+              ).with_hint("addresses are written as 0x followed by 4 hex digits".to_string()),
+            );
+            line_counter += 1;
+            continue;
+          }
+          None => match self.expression_bytes(&split_line[1], 2, DiagnosticKind::DataError, line_counter, line, "data address") {
+            Ok(ref address_bytes) if address_bytes[0].byte_expression.is_none() => {
+              address = (address_bytes[0].byte_value as u16) | ((address_bytes[1].byte_value as u16) << 8);
             }
-            if let Some(data) = Assembler::parse_value(split_line[2].as_str()) {
-              data_entries.push((address, data, line_counter));
-            } else {
-              return Err(format!("Data Value Error on line {}", line_counter).to_string());
+            Ok(..) => {
+              diagnostics.push(
+                Diagnostic::error(
+                  DiagnosticKind::DataError,
+                  line_counter,
+                  split_line[1].span(),
+                  format!("data address \"{}\" refers to a label", split_line[1].text),
+                  line,
+                ).with_hint("data addresses must be known before labels are placed".to_string()),
+              );
+              line_counter += 1;
+              continue;
             }
-          } else {
-            return Err(format!("Data Address Error on line {}", line_counter).to_string());
+            Err(diagnostic) => {
+              diagnostics.push(*diagnostic);
+              line_counter += 1;
+              continue;
+            }
+          },
+        }
+        let data: Result<Vec<Byte>, Box<Diagnostic>> = match self.parse_value(split_line[2].text.as_str()) {
+          Some(data) => Ok(data),
+          None => {
+            let width: usize = match self.parse_expression(split_line[2].text.as_str()) {
+              Ok(ref parsed) if parsed.is_byte() => 1,
+              _ => 2,
+            };
+            self.expression_bytes(&split_line[2], width, DiagnosticKind::DataError, line_counter, line, "data value")
           }
-        } else {
-          return Err(format!("Data Length Error on line {}", line_counter).to_string());
+        };
+        match data {
+          Ok(mut data) => {
+            for data_byte in data.iter_mut() {
+              data_byte.byte_line = line_counter;
+              data_byte.byte_span = split_line[2].span();
+            }
+            self.qualify_references(&mut data, line_counter);
+            data_entries.push((address, data, line_counter, split_line[1].clone(), line.to_string()));
+          }
+          Err(diagnostic) => diagnostics.push(*diagnostic),
+        }
+        line_counter += 1;
+        continue;
+      }
+      if Assembler::is_inline_data_directive(line) {
+        let directive: String = Assembler::split_line(line)[0].text.to_uppercase();
+        let is_padding: bool = directive == ".ORG" || directive == ".ALIGN";
+        match self.assemble_data_directive(line, line_counter, result.len()) {
+          Ok(mut data) => {
+            if !past_end_reported && result.len() + data.len() > 65536 {
+              diagnostics.push(Assembler::past_end_of_memory(line, line_counter, result.len(), data.len()));
+              past_end_reported = true;
+            }
+            self.qualify_references(&mut data, line_counter);
+            for (index, mut byte) in data.into_iter().enumerate() {
+              if !is_padding {
+                byte.byte_line = line_counter;
+              }
+              if index == 0 && !is_padding {
+                byte.byte_attached_labels = labels_from_previous.iter().map(|label| label.name.clone()).collect();
+                labels_from_previous.clear();
+              }
+              result.push(byte);
+            }
+          }
+          Err(diagnostic) => diagnostics.push(*diagnostic),
         }
         line_counter += 1;
         continue;
       }
-      match self.assemble_line_to_bytecode(line) {
-        Ok(line_bytecode) => {
-          for byte in line_bytecode {
+      match self.assemble_line_to_bytecode(line, line_counter) {
+        Ok(mut line_bytecode) => {
+          if !past_end_reported && result.len() + line_bytecode.len() > 65536 {
+            diagnostics.push(Assembler::past_end_of_memory(line, line_counter, result.len(), line_bytecode.len()));
+            past_end_reported = true;
+          }
+          self.qualify_references(&mut line_bytecode, line_counter);
+          for mut byte in line_bytecode {
+            byte.byte_line = line_counter;
             match byte.byte_type {
               ByteType::OpCode => {
                 let mut adjusted_byte: Byte = byte.clone();
-                adjusted_byte.byte_attached_labels = labels_from_previous.clone();
+                adjusted_byte.byte_attached_labels =
+                  labels_from_previous.iter().map(|label| label.name.clone()).collect();
                 result.push(adjusted_byte);
                 labels_from_previous.clear();
               }
               _ => result.push(byte),
             }
           }
-          line_counter += 1;
         }
-        Err(err) => return Err(format!("{} on line {}", err, line_counter).to_string()),
+        Err(diagnostic) => diagnostics.push(*diagnostic),
       }
+      line_counter += 1;
+    }
+    for label in labels_from_previous.iter() {
+      let source_line: &str = self.expanded.lines().nth(label.line - 1).unwrap_or("");
+      let column: usize = source_line.chars().take_while(|chr| chr.is_whitespace()).count();
+      let written: String = source_line.trim().chars().skip(1).collect::<String>();
+      diagnostics.push(
+        Diagnostic::warning(
+          DiagnosticKind::DanglingLabel,
+          label.line,
+          (column, source_line.trim_end().chars().count()),
+          format!("label \"{}\" is not followed by an instruction or data", written),
+          source_line,
+        ).with_hint("labels are attached to the next instruction or data, add one (such as HALT) after it".to_string()),
+      );
     }
     for entry in data_entries {
-      let (address, data, on_line) = entry;
+      let (address, data, on_line, address_item, source_line) = entry;
       if result.len() > address as usize {
-        return Err(format!("Data directive on line {} attempted to overwrite data at address 0x{:04x}", on_line, address).to_string());
+        diagnostics.push(
+          Diagnostic::error(
+            DiagnosticKind::DataError,
+            on_line,
+            address_item.span(),
+            format!("data directive attempted to overwrite data at address 0x{:04x}", address),
+            source_line.as_str(),
+          ).with_hint(format!(
+            "data must be placed after code and earlier data, which ends at 0x{:04x}",
+            result.len()
+          )),
+        );
+        continue;
+      }
+      if address as usize + data.len() > 65536 {
+        diagnostics.push(Assembler::past_end_of_memory(source_line.as_str(), on_line, address as usize, data.len()));
+        continue;
       }
       while result.len() < address as usize {
         result.push(Byte::from_u8(0x00));
@@ -210,47 +1459,747 @@ impl Assembler {
         result.push(data_byte);
       }
     }
+    self.diagnostics.extend(diagnostics);
+    return result;
+  }
+}
+
+/// Error for a line whose bytes would be placed past the last address, 0xffff
+impl Assembler {
+  fn past_end_of_memory(line: &str, line_number: usize, location: usize, length: usize) -> Diagnostic {
+    let column: usize = line.chars().take_while(|chr| chr.is_whitespace()).count();
+    return Diagnostic::error(
+      DiagnosticKind::DataError,
+      line_number,
+      (column, line.trim_end().chars().count()),
+      format!("{} byte(s) at 0x{:04x} run past the end of memory", length, location),
+      line,
+    ).with_hint("programs must fit in the 65536 bytes of memory, from 0x0000 to 0xffff".to_string());
+  }
+}
+
+impl Assembler {
+  fn is_inline_data_directive(line: &str) -> bool {
+    match Assembler::split_line(line).first() {
+      Some(first) => {
+        let first: String = first.text.to_uppercase();
+        return first == ".BYTE" || first == ".WORD" || first == ".SPACE" || first == ".ORG" || first == ".ALIGN";
+      }
+      None => return false,
+    }
+  }
+}
+
+/// Assembles .BYTE, .WORD and .SPACE into the bytes they place at the current location, and .ORG
+/// and .ALIGN into the zeroes which move the location on
+impl Assembler {
+  fn assemble_data_directive(&self, line: &str, line_number: usize, location: usize) -> Result<Vec<Byte>, Box<Diagnostic>> {
+    let split_line: Vec<LineItem> = Assembler::split_line(line);
+    let directive: String = split_line[0].text.to_uppercase();
+    let (minimum, maximum, expected, usage): (usize, usize, &str, &str) = match directive.as_str() {
+      ".BYTE" => (1, usize::MAX, "at least 1 value", "use .BYTE VALUE..."),
+      ".WORD" => (1, usize::MAX, "at least 1 value", "use .WORD VALUE..."),
+      ".SPACE" => (1, 2, "1 or 2 arguments", "use .SPACE LENGTH [FILL]"),
+      ".ORG" => (1, 1, "1 argument", "use .ORG ADDRESS"),
+      ".ALIGN" => (1, 1, "1 argument", "use .ALIGN BOUNDARY"),
+      _ => {
+        return Err(Box::new(
+          Diagnostic::error(
+            DiagnosticKind::DataError,
+            line_number,
+            split_line[0].span(),
+            format!("unknown data directive \"{}\"", split_line[0].text),
+            line,
+          ).with_hint("data directives are .BYTE, .WORD, .SPACE, .ORG and .ALIGN".to_string()),
+        ));
+      }
+    };
+    let arguments: usize = split_line.len() - 1;
+    if arguments < minimum || arguments > maximum {
+      return Err(Box::new(
+        Diagnostic::error(
+          DiagnosticKind::DataError,
+          line_number,
+          (split_line[0].column, line.trim_end().chars().count()),
+          format!("{} directive expects {} but got {}", directive, expected, arguments),
+          line,
+        ).with_hint(usage.to_string()),
+      ));
+    }
+
+    let mut result: Vec<Byte> = Vec::new();
+    match directive.as_str() {
+      ".BYTE" | ".WORD" => {
+        let width: usize = if directive == ".BYTE" { 1 } else { 2 };
+        for item in split_line.iter().skip(1) {
+          let mut bytes: Vec<Byte> = match self.parse_value(item.text.as_str()) {
+            Some(bytes) => bytes,
+            None => self.expression_bytes(item, width, DiagnosticKind::DataError, line_number, line, "data value")?,
+          };
+          let is_label: bool = bytes.iter().any(|byte| matches!(byte.byte_type, ByteType::Label));
+          if width == 1 && is_label {
+            return Err(Box::new(
+              Diagnostic::error(
+                DiagnosticKind::DataError,
+                line_number,
+                item.span(),
+                format!("data value \"{}\" is 2 bytes long", item.text),
+                line,
+              ).with_hint("use .WORD for 16-bit values, or <:NAME and >:NAME for the bytes of a label".to_string()),
+            ));
+          }
+          // Values are checked whatever they were written as, so 0x0012 is a byte but a constant
+          // holding 0x1234 isn't
+          if width == 1 && bytes.len() > 1 && !self.is_string_value(item.text.as_str()) {
+            if bytes[1..].iter().any(|byte| byte.byte_value != 0) {
+              return Err(Box::new(
+                Diagnostic::error(
+                  DiagnosticKind::DataError,
+                  line_number,
+                  item.span(),
+                  format!("data value \"{}\" does not fit in 1 byte", item.text),
+                  line,
+                ).with_hint("use .WORD for 16-bit values".to_string()),
+              ));
+            }
+            bytes.truncate(1);
+          }
+          if width == 2 && bytes.len() > 2 {
+            return Err(Box::new(
+              Diagnostic::error(
+                DiagnosticKind::DataError,
+                line_number,
+                item.span(),
+                format!("data value {} is more than 2 bytes long", item.text),
+                line,
+              ).with_hint("use .BYTE for strings".to_string()),
+            ));
+          }
+          if width == 2 && bytes.len() == 1 {
+            bytes.push(Byte::from_u8(0x00));
+          }
+          for mut byte in bytes {
+            byte.byte_span = item.span();
+            result.push(byte);
+          }
+        }
+      }
+      ".SPACE" => {
+        let length: usize = self.constant_operand(&split_line[1], line_number, line, "space length")? as usize;
+        let mut fill: u8 = 0;
+        if arguments == 2 {
+          let value: i64 = self.constant_operand(&split_line[2], line_number, line, "fill byte")?;
+          if value > 0xff {
+            return Err(Box::new(Diagnostic::error(
+              DiagnosticKind::DataError,
+              line_number,
+              split_line[2].span(),
+              format!("fill byte \"{}\" does not fit in 1 byte", split_line[2].text),
+              line,
+            )));
+          }
+          fill = value as u8;
+        }
+        if location + length > 65536 {
+          return Err(Box::new(Diagnostic::error(
+            DiagnosticKind::DataError,
+            line_number,
+            split_line[1].span(),
+            format!("space of {} bytes at 0x{:04x} runs past the end of memory", length, location),
+            line,
+          )));
+        }
+        for _ in 0..length {
+          let mut byte: Byte = Byte::from_u8(fill);
+          byte.byte_span = split_line[1].span();
+          result.push(byte);
+        }
+      }
+      ".ORG" => {
+        let address: usize = self.constant_operand(&split_line[1], line_number, line, "origin")? as usize;
+        if address > 65536 {
+          return Err(Box::new(Diagnostic::error(
+            DiagnosticKind::DataError,
+            line_number,
+            split_line[1].span(),
+            format!("origin 0x{:04x} is past the end of memory", address),
+            line,
+          )));
+        }
+        if address < location {
+          return Err(Box::new(
+            Diagnostic::error(
+              DiagnosticKind::DataError,
+              line_number,
+              split_line[1].span(),
+              format!("origin 0x{:04x} is before the current location 0x{:04x}", address, location),
+              line,
+            ).with_hint("code and data can't overlap, so .ORG can only move forward".to_string()),
+          ));
+        }
+        for _ in location..address {
+          result.push(Byte::from_u8(0x00));
+        }
+      }
+      ".ALIGN" => {
+        let boundary: usize = self.constant_operand(&split_line[1], line_number, line, "alignment")? as usize;
+        if boundary == 0 {
+          return Err(Box::new(Diagnostic::error(
+            DiagnosticKind::DataError,
+            line_number,
+            split_line[1].span(),
+            "alignment must be at least 1".to_string(),
+            line,
+          )));
+        }
+        for _ in 0..(boundary - location % boundary) % boundary {
+          result.push(Byte::from_u8(0x00));
+        }
+      }
+      // Any other directive was rejected above
+      _ => {}
+    }
     return Ok(result);
   }
 }
 
+/// Whether a value is a string, either in quotes or a constant defined as one
 impl Assembler {
-  pub fn check_labels(&mut self) -> Result<(), String> {
-    let mut line_counter: usize = 1;
-    let mut already_used: Vec<String> = Vec::new();
-    for line in self.program.trim().lines() {
+  fn is_string_value(&self, text: &str) -> bool {
+    match self.constants.get(text) {
+      Some(constant) => constant.string,
+      None => text.starts_with('"'),
+    }
+  }
+}
+
+/// Parses an operand which must be known before labels are placed, such as an address or length,
+/// into a value between 0 and 0xffff
+impl Assembler {
+  fn constant_operand(&self, item: &LineItem, line_number: usize, line: &str, description: &str) -> Result<i64, Box<Diagnostic>> {
+    let parsed: Expression;
+    match self.parse_expression(item.text.as_str()) {
+      Ok(expression) => parsed = expression,
+      Err(message) => {
+        return Err(Box::new(self.expression_error(DiagnosticKind::DataError, item, line_number, line, description, message)))
+      }
+    }
+    if !parsed.labels().is_empty() {
+      return Err(Box::new(
+        Diagnostic::error(
+          DiagnosticKind::DataError,
+          line_number,
+          item.span(),
+          format!("{} \"{}\" refers to a label", description, item.text),
+          line,
+        ).with_hint(format!("the {} must be known before labels are placed", description)),
+      ));
+    }
+    match parsed.evaluate(&|_: &str| None) {
+      Ok(value) if (0..=0xffff).contains(&value) => return Ok(value),
+      Ok(value) => {
+        return Err(Box::new(
+          Diagnostic::error(
+            DiagnosticKind::DataError,
+            line_number,
+            item.span(),
+            format!("{} {} is out of range", description, value),
+            line,
+          ).with_hint("it must be between 0 and 0xffff".to_string()),
+        ))
+      }
+      Err(message) => return Err(Box::new(Diagnostic::error(DiagnosticKind::DataError, line_number, item.span(), message, line))),
+    }
+  }
+}
+
+/// Finds the global label each line of the program belongs to, which local labels are scoped to,
+/// and where the anonymous labels are, labels from macro expansions don't start a new scope and
+/// are left out of the object's symbols
+impl Assembler {
+  fn collect_scopes(&mut self) {
+    self.scopes.clear();
+    self.anonymous_labels.clear();
+    self.macro_labels.clear();
+    let mut scope: String = String::new();
+    for (index, line) in self.expanded.lines().enumerate() {
+      let line_counter: usize = index + 1;
       if line.trim().starts_with(':') {
-        let label: String = line.trim().chars().skip(1).collect::<String>();
-        if already_used.contains(&label) {
-          return Err(format!(
-            "Ambiguous Input Error: redefinition of label \"{}\" on line {}",
-            label,
-            line_counter
+        let written: String = line.trim().chars().skip(1).collect::<String>();
+        let from_macro: bool = match self.origins.get(line_counter - 1) {
+          Some(origin) => !origin.expansions.is_empty(),
+          None => false,
+        };
+        if written == "+" || written == "-" {
+          self.anonymous_labels.entry(written.chars().next().unwrap()).or_default().push(line_counter);
+        } else if !written.is_empty() && !written.starts_with('.') && !from_macro {
+          scope = written;
+        }
+      }
+      self.scopes.push(scope.clone());
+      if line.trim().starts_with(':') && self.origins.get(line_counter - 1).is_some_and(|origin| !origin.expansions.is_empty()) {
+        let written: String = line.trim().chars().skip(1).collect::<String>();
+        let name: String = self.qualify_definition(written.as_str(), line_counter);
+        self.macro_labels.insert(name);
+      }
+    }
+  }
+}
+
+/// Whether a label name is a reference to an anonymous label, such as + or --
+impl Assembler {
+  fn is_anonymous_reference(name: &str) -> bool {
+    !name.is_empty() && (name.chars().all(|chr| chr == '+') || name.chars().all(|chr| chr == '-'))
+  }
+}
+
+/// Whether a label name is the name given to an anonymous label where it is defined
+impl Assembler {
+  fn is_anonymous_definition(name: &str) -> bool {
+    (name.starts_with('+') || name.starts_with('-')) && name.contains('@')
+  }
+}
+
+/// Gives the label defined on a line its full name, local labels (.NAME) are prefixed with the
+/// global label they belong to and anonymous labels are named after their line
+impl Assembler {
+  fn qualify_definition(&self, name: &str, line_number: usize) -> String {
+    if name == "+" || name == "-" {
+      return format!("{}@{}", name, line_number);
+    }
+    if name.starts_with('.') {
+      return format!("{}{}", self.scopes.get(line_number - 1).map(|scope| scope.as_str()).unwrap_or(""), name);
+    }
+    return name.to_string();
+  }
+}
+
+/// Gives a label referred to on a line its full name, :+ refers to the next anonymous :+ label
+/// (:++ the one after that) and :- to the previous :- label, references which can't be resolved
+/// are left alone to be reported as undefined
+impl Assembler {
+  fn qualify_reference(&self, name: &str, line_number: usize) -> String {
+    if !Assembler::is_anonymous_reference(name) {
+      return self.qualify_definition(name, line_number);
+    }
+    let kind: char = name.chars().next().unwrap();
+    let lines: &[usize] = match self.anonymous_labels.get(&kind) {
+      Some(lines) => lines.as_slice(),
+      None => return name.to_string(),
+    };
+    let found: Option<usize> = if kind == '+' {
+      let after: usize = lines.partition_point(|&line| line <= line_number);
+      lines.get(after + name.len() - 1).cloned()
+    } else {
+      let before: usize = lines.partition_point(|&line| line < line_number);
+      before.checked_sub(name.len()).map(|index| lines[index])
+    };
+    match found {
+      Some(line) => return format!("{}@{}", kind, line),
+      None => return name.to_string(),
+    }
+  }
+}
+
+/// Gives every label referred to by the bytes of a line its full name
+impl Assembler {
+  fn qualify_references(&self, bytes: &mut [Byte], line_number: usize) {
+    for byte in bytes.iter_mut() {
+      match byte.byte_type {
+        ByteType::Label | ByteType::LabelPadding if !byte.byte_label.is_empty() => {
+          byte.byte_label = self.qualify_reference(byte.byte_label.as_str(), line_number);
+        }
+        ByteType::Expression => {
+          if let Some(ref expression) = byte.byte_expression {
+            byte.byte_expression = Some(expression.map_labels(&|name: &str| self.qualify_reference(name, line_number)));
+          }
+        }
+        _ => {}
+      }
+    }
+  }
+}
+
+impl Assembler {
+  fn check_labels(&mut self) {
+    let mut already_used: HashSet<String> = HashSet::new();
+    for (index, line) in self.expanded.lines().enumerate() {
+      let line_counter: usize = index + 1;
+      if line.trim().starts_with(':') {
+        let written: String = line.trim().chars().skip(1).collect::<String>();
+        let column: usize = line.chars().take_while(|chr| chr.is_whitespace()).count();
+        let span: (usize, usize) = (column, column + 1 + written.chars().count());
+        let label: String = self.qualify_definition(written.as_str(), line_counter);
+        if written.is_empty() || written == "." {
+          self.diagnostics.push(Diagnostic::error(
+            DiagnosticKind::SyntaxError,
+            line_counter,
+            span,
+            "label has no name".to_string(),
+            line,
           ));
+        } else if Assembler::is_anonymous_reference(written.as_str()) && written.len() > 1 {
+          self.diagnostics.push(
+            Diagnostic::error(
+              DiagnosticKind::SyntaxError,
+              line_counter,
+              span,
+              format!("anonymous label \":{}\" can only be referred to, not defined", written),
+              line,
+            ).with_hint("define anonymous labels with :+ or :-, and skip over them with :++ or :--".to_string()),
+          );
+        } else if already_used.contains(&label) {
+          self.diagnostics.push(Diagnostic::error(
+            DiagnosticKind::AmbiguousInput,
+            line_counter,
+            span,
+            format!("redefinition of label \"{}\"", label),
+            line,
+          ));
+        } else {
+          already_used.insert(label);
+        }
+      }
+    }
+  }
+}
+
+/// Collects the .EQU and .CONST directives before assembly so constants can be used anywhere in
+/// the program, a constant's value may refer to constants defined above it
+impl Assembler {
+  fn collect_constants(&mut self) {
+    self.constants.clear();
+    let program: String = self.expanded.clone();
+    let mut line_counter: usize = 1;
+    for line in program.lines() {
+      if !Assembler::is_constant_directive(line) {
+        line_counter += 1;
+        continue;
+      }
+      let split_line: Vec<LineItem> = Assembler::split_line(line);
+      if split_line.len() != 3 {
+        self.diagnostics.push(
+          Diagnostic::error(
+            DiagnosticKind::SyntaxError,
+            line_counter,
+            (split_line[0].column, line.trim_end().chars().count()),
+            format!("constant directive expects 2 arguments but got {}", split_line.len() - 1),
+            line,
+          ).with_hint(format!("use {} NAME VALUE", split_line[0].text.to_uppercase())),
+        );
+      } else if !Assembler::is_identifier(split_line[1].text.as_str()) {
+        self.diagnostics.push(
+          Diagnostic::error(
+            DiagnosticKind::SyntaxError,
+            line_counter,
+            split_line[1].span(),
+            format!("invalid constant name \"{}\"", split_line[1].text),
+            line,
+          ).with_hint("constant names start with a letter or _ and contain only letters, digits and _".to_string()),
+        );
+      } else if let Some(existing) = self.constants.get(&split_line[1].text) {
+        self.diagnostics.push(
+          Diagnostic::error(
+            DiagnosticKind::AmbiguousInput,
+            line_counter,
+            split_line[1].span(),
+            format!("redefinition of constant \"{}\"", split_line[1].text),
+            line,
+          ).with_hint(format!("first defined on {}", self.describe_line(existing.line))),
+        );
+      } else {
+        let mut constant: Constant = Constant {
+          line: line_counter,
+          value: vec![],
+          expression: None,
+          string: self.is_string_value(split_line[2].text.as_str()),
+        };
+        match self.parse_value(split_line[2].text.as_str()) {
+          Some(value) => constant.value = value,
+          None => match self.parse_expression(split_line[2].text.as_str()) {
+            Ok(parsed) => constant.expression = Some(parsed),
+            Err(message) => {
+              let diagnostic: Diagnostic = self.expression_error(
+                DiagnosticKind::ValueError,
+                &split_line[2],
+                line_counter,
+                line,
+                "constant value",
+                message,
+              );
+              self.diagnostics.push(diagnostic);
+              line_counter += 1;
+              continue;
+            }
+          },
         }
-        already_used.push(label);
+        self.constants.insert(split_line[1].text.clone(), constant);
       }
       line_counter += 1;
     }
-    return Ok(());
   }
 }
 
 impl Assembler {
-  fn split_line(line: &str) -> Vec<String> {
-    let mut result: Vec<String> = Vec::new();
+  fn is_constant_directive(line: &str) -> bool {
+    match line.split_whitespace().next() {
+      Some(first) => {
+        let first: String = first.to_uppercase();
+        first == ".EQU" || first == ".CONST"
+      }
+      None => false,
+    }
+  }
+}
+
+impl Assembler {
+  fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+      Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+        chars.all(|chr| chr.is_ascii_alphanumeric() || chr == '_')
+      }
+      _ => false,
+    }
+  }
+}
+
+/// Builds the error for a value which could not be parsed, calling out unknown constants
+impl Assembler {
+  fn value_error(
+    &self,
+    kind: DiagnosticKind,
+    item: &LineItem,
+    line_number: usize,
+    line: &str,
+    description: &str,
+  ) -> Diagnostic {
+    if Assembler::is_identifier(item.text.as_str()) {
+      let mut diagnostic: Diagnostic = Diagnostic::error(
+        DiagnosticKind::UnknownConstant,
+        line_number,
+        item.span(),
+        format!("unknown constant \"{}\"", item.text),
+        line,
+      );
+      let names = self.constants.keys().map(|name| name.as_str());
+      if let Some(name) = diagnostic::closest_match(item.text.as_str(), names) {
+        diagnostic = diagnostic.with_hint(format!("did you mean \"{}\"?", name));
+      } else {
+        diagnostic = diagnostic.with_hint(format!(
+          "define it with .EQU {} VALUE, or use :{} for a label",
+          item.text,
+          item.text
+        ));
+      }
+      return diagnostic;
+    }
+    return Diagnostic::error(
+      kind,
+      line_number,
+      item.span(),
+      format!("could not parse {} \"{}\"", description, item.text),
+      line,
+    );
+  }
+}
+
+/// Builds the error for an operand which could not be parsed as an expression
+impl Assembler {
+  fn expression_error(
+    &self,
+    kind: DiagnosticKind,
+    item: &LineItem,
+    line_number: usize,
+    line: &str,
+    description: &str,
+    message: String,
+  ) -> Diagnostic {
+    if Assembler::is_identifier(item.text.as_str()) {
+      return self.value_error(kind, item, line_number, line, description);
+    }
+    return Diagnostic::error(
+      kind,
+      line_number,
+      item.span(),
+      format!("could not parse {} \"{}\"", description, item.text),
+      line,
+    ).with_hint(message);
+  }
+}
+
+impl Assembler {
+  fn parse_expression(&self, text: &str) -> Result<Expression, String> {
+    return expression::parse(text, &|name: &str| self.constant_expression(name));
+  }
+}
+
+/// Turns a constant into an expression so it can be used as part of a larger one
+impl Assembler {
+  fn constant_expression(&self, name: &str) -> Result<Expression, String> {
+    let constant: &Constant = match self.constants.get(name) {
+      Some(found) => found,
+      None => return Err(format!("unknown constant \"{}\"", name)),
+    };
+    if let Some(ref expression) = constant.expression {
+      return Ok(expression.clone());
+    }
+    let value: &[Byte] = constant.value.as_slice();
+    match value.first().map(|byte| &byte.byte_type) {
+      Some(&ByteType::Label) => return Ok(Expression::Label(value[0].byte_label.clone())),
+      Some(&ByteType::Data) if value.len() <= 2 => {
+        let mut number: i64 = 0;
+        for (index, byte) in value.iter().enumerate() {
+          number |= (byte.byte_value as i64) << (8 * index);
+        }
+        return Ok(Expression::Number(number));
+      }
+      _ => return Err(format!("constant \"{}\" is not a number", name)),
+    }
+  }
+}
+
+/// Parses an operand as an expression of the given width in bytes, expressions which don't refer
+/// to labels are evaluated straight away and the rest are left for label resolution
+impl Assembler {
+  fn expression_bytes(
+    &self,
+    item: &LineItem,
+    width: usize,
+    kind: DiagnosticKind,
+    line_number: usize,
+    line: &str,
+    description: &str,
+  ) -> Result<Vec<Byte>, Box<Diagnostic>> {
+    let parsed: Expression = match self.parse_expression(item.text.as_str()) {
+      Ok(expression) => expression,
+      Err(message) => return Err(Box::new(self.expression_error(kind, item, line_number, line, description, message))),
+    };
+
+    let mut result: Vec<Byte> = Vec::new();
+    if parsed.labels().is_empty() {
+      match parsed.evaluate(&|_: &str| None).and_then(|value| expression::encode(value, width)) {
+        Ok(bytes) => {
+          for byte in bytes {
+            result.push(Byte::from_u8(byte));
+          }
+        }
+        Err(message) => {
+          return Err(Box::new(Diagnostic::error(kind, line_number, item.span(), message, line)));
+        }
+      }
+    } else {
+      result.push(Byte {
+        byte_type: ByteType::Expression,
+        byte_value: width as u8,
+        byte_label: "".to_string(),
+        byte_attached_labels: vec![],
+        byte_line: 0,
+        byte_span: (0, 0),
+        byte_expression: Some(parsed),
+      });
+      for _ in 1..width {
+        result.push(Byte {
+          byte_type: ByteType::LabelPadding,
+          byte_value: 0,
+          byte_label: "".to_string(),
+          byte_attached_labels: vec![],
+          byte_line: 0,
+          byte_span: (0, 0),
+          byte_expression: None,
+        });
+      }
+    }
+    for byte in result.iter_mut() {
+      byte.byte_span = item.span();
+    }
+    return Ok(result);
+  }
+}
+
+/// Checks every label reference resolves to a label attached to an instruction, as unresolved
+/// references would otherwise assemble to nothing and misalign everything after them
+impl Assembler {
+  fn check_label_references(&mut self, partially_compiled_bytecode: &[Byte]) {
+    let mut dangling: Vec<Label> = Vec::new();
+    for (index, line) in self.expanded.lines().enumerate() {
+      let line_counter: usize = index + 1;
+      if line.trim().starts_with(':') {
+        let written: String = line.trim().chars().skip(1).collect::<String>();
+        let name: String = self.qualify_definition(written.as_str(), line_counter);
+        if !self.symbols.contains_key(&name) {
+          dangling.push(Label {
+            line: line_counter,
+            name,
+          });
+        }
+      }
+    }
+
+    for byte in partially_compiled_bytecode.iter() {
+      let referenced: Vec<String> = match byte.byte_type {
+        ByteType::Label => vec![byte.byte_label.clone()],
+        ByteType::Expression => match byte.byte_expression {
+          Some(ref expression) => expression.labels(),
+          None => vec![],
+        },
+        _ => continue,
+      };
+      for reference in referenced {
+        if self.symbols.contains_key(&reference) || self.is_imported(reference.as_str()) {
+          continue;
+        }
+        let source_line: &str = self.expanded.lines().nth(byte.byte_line - 1).unwrap_or("");
+        let mut diagnostic: Diagnostic = Diagnostic::error(
+          DiagnosticKind::UndefinedLabel,
+          byte.byte_line,
+          byte.byte_span,
+          format!("undefined label \"{}\"", reference),
+          source_line,
+        );
+        if Assembler::is_anonymous_reference(reference.as_str()) {
+          let direction: &str = if reference.starts_with('+') { "after" } else { "before" };
+          diagnostic = diagnostic.with_hint(match reference.len() {
+            1 => format!("there is no \":{}\" label {} this line", reference, direction),
+            count => format!("there are fewer than {} \":{}\" labels {} this line", count, &reference[..1], direction),
+          });
+        } else if let Some(label) = dangling.iter().find(|label| label.name == reference) {
+          diagnostic = diagnostic.with_hint(format!(
+            "\"{}\" is defined on {} but is not followed by an instruction or data",
+            label.name,
+            self.describe_line(label.line)
+          ));
+        } else if let Some(name) =
+          diagnostic::closest_match(reference.as_str(), self.defined_labels().into_iter())
+        {
+          diagnostic = diagnostic.with_hint(format!("did you mean \":{}\"?", name));
+        }
+        self.diagnostics.push(diagnostic);
+      }
+    }
+  }
+}
+
+impl Assembler {
+  fn split_line(line: &str) -> Vec<LineItem> {
+    let mut result: Vec<LineItem> = Vec::new();
     let mut line_item: String = String::new();
+    let mut line_item_column: usize = 0;
     let mut in_line_item: bool = false;
     let mut in_quote = false;
     let mut escape = false;
-    for item in line.trim().chars() {
+    let offset: usize = line.chars().take_while(|chr| chr.is_whitespace()).count();
+    for (index, item) in line.trim().chars().enumerate() {
       if item == ' ' {
         if in_line_item {
           if in_quote {
             line_item.push(item);
           } else {
             in_line_item = false;
-            result.push(line_item.clone());
+            result.push(LineItem {
+              text: line_item.clone(),
+              column: line_item_column,
+            });
             line_item = "".to_string();
           }
         }
@@ -258,59 +2207,78 @@ impl Assembler {
       } else {
         if !in_line_item {
           in_line_item = true;
+          line_item_column = offset + index;
         }
         if item == '\\' {
           escape = true;
           line_item.push(item);
           continue;
         }
-        if item == '"' || item == '\'' {
-          if !escape {
-            if in_quote {
-              in_quote = false;
-            } else {
-              in_quote = true;
-            }
-          }
+        if (item == '"' || item == '\'') && !escape {
+          in_quote = !in_quote;
         }
         line_item.push(item);
         escape = false;
       }
     }
     if in_line_item {
-      result.push(line_item.clone());
+      result.push(LineItem {
+        text: line_item.clone(),
+        column: line_item_column,
+      });
     }
     return result;
   }
 }
 
 impl Assembler {
-  fn assemble_line_to_bytecode(&self, line: &str) -> Result<Vec<Byte>, AssembleError> {
+  fn assemble_line_to_bytecode(&self, line: &str, line_number: usize) -> Result<Vec<Byte>, Box<Diagnostic>> {
     let mut result: Vec<Byte> = Vec::new();
-    let split_line: Vec<String> = Assembler::split_line(line);
-    let inst: Instruction;
+    let split_line: Vec<LineItem> = Assembler::split_line(line);
+    let inst: &Instruction;
 
-    if split_line.len() > 0 {
-      match instructions::find_inst_by_name(split_line[0].as_str()) {
+    if !split_line.is_empty() {
+      match instructions::find_inst_by_name(split_line[0].text.as_str()) {
         Some(i) => {
           inst = i;
         }
         None => {
-          return Err(AssembleError {
-            error_type: "Syntax Error".to_string(),
-            error_description: format!("unknown instruction \"{}\"", split_line[0]),
-          })
+          let mut diagnostic: Diagnostic = Diagnostic::error(
+            DiagnosticKind::SyntaxError,
+            line_number,
+            split_line[0].span(),
+            format!("unknown instruction \"{}\"", split_line[0].text),
+            line,
+          );
+          let names = instructions::INSTRUCTIONS.iter().map(|instruction| instruction.inst);
+          if let Some(name) = diagnostic::closest_match(split_line[0].text.as_str(), names) {
+            diagnostic = diagnostic.with_hint(format!("did you mean \"{}\"?", name));
+          }
+          return Err(Box::new(diagnostic));
         }
       }
       if inst.num_args as usize != split_line.len() - 1 {
-        return Err(AssembleError {
-          error_type: "Syntax Error".to_string(),
-          error_description: format!(
-            "argument count mismatch, expected {} but got {},",
-            inst.num_args,
-            split_line.len() - 1
-          ),
-        });
+        let span: (usize, usize) = if split_line.len() - 1 > inst.num_args as usize {
+          (
+            split_line[inst.num_args as usize + 1].column,
+            split_line[split_line.len() - 1].span().1,
+          )
+        } else {
+          split_line[0].span()
+        };
+        return Err(Box::new(
+          Diagnostic::error(
+            DiagnosticKind::SyntaxError,
+            line_number,
+            span,
+            format!(
+              "argument count mismatch, expected {} but got {}",
+              inst.num_args,
+              split_line.len() - 1
+            ),
+            line,
+          ).with_hint(format!("{} takes {} argument(s)", inst.inst, inst.num_args)),
+        ));
       }
 
       result.push(Byte {
@@ -318,49 +2286,58 @@ impl Assembler {
         byte_value: inst.op_code,
         byte_label: "".to_string(),
         byte_attached_labels: vec![],
+        byte_line: 0,
+        byte_span: (0, 0),
+        byte_expression: None,
       });
 
       let mut byte_sum: usize = 0;
       if split_line.len() > 1 {
         let rest = split_line.clone().split_off(1);
 
-        for item in rest {
-          match Assembler::parse_value(item.as_str()) {
-            Some(bytes) => for byte in bytes {
-              result.push(byte);
-              byte_sum += 1;
-            },
-            None => {
-              return Err(AssembleError {
-                error_type: "Value Error".to_string(),
-                error_description: format!("could not parse argument \"{}\"", item),
-              })
-            }
+        for item in rest.iter() {
+          let bytes: Vec<Byte> = match self.parse_value(item.text.as_str()) {
+            Some(bytes) => bytes,
+            None => self.expression_bytes(
+              item,
+              inst.bytes_per_arg as usize,
+              DiagnosticKind::ValueError,
+              line_number,
+              line,
+              "argument",
+            )?,
+          };
+          for mut byte in bytes {
+            byte.byte_span = item.span();
+            result.push(byte);
+            byte_sum += 1;
           }
         }
       }
 
-      let inst_size: usize = (inst.bytes_per_arg as usize * inst.num_args as usize) as usize;
+      let inst_size: usize = inst.bytes_per_arg as usize * inst.num_args as usize;
 
-      if byte_sum > inst_size {
-        return Err(AssembleError {
-          error_type: "Syntax Error".to_string(),
-          error_description: format!(
-            "argument(s) too many bytes, expected {} but got {},",
-            inst_size,
-            byte_sum
-          ),
-        });
-      }
-      if byte_sum < inst_size {
-        return Err(AssembleError {
-          error_type: "Syntax Error".to_string(),
-          error_description: format!(
-            "argument(s) too few bytes, expected {} but got {},",
-            inst_size,
-            byte_sum
-          ),
-        });
+      if byte_sum != inst_size {
+        let span: (usize, usize) = (split_line[1].column, split_line[split_line.len() - 1].span().1);
+        let hint: String = match inst.bytes_per_arg {
+          1 => format!("{} takes 1 byte arguments, such as 0x00", inst.inst),
+          _ => format!("{} takes {} byte arguments, such as 0x0000", inst.inst, inst.bytes_per_arg),
+        };
+        let amount: &str = if byte_sum > inst_size { "many" } else { "few" };
+        return Err(Box::new(
+          Diagnostic::error(
+            DiagnosticKind::SyntaxError,
+            line_number,
+            span,
+            format!(
+              "argument(s) too {} bytes, expected {} but got {}",
+              amount,
+              inst_size,
+              byte_sum
+            ),
+            line,
+          ).with_hint(hint),
+        ));
       }
     }
 
@@ -369,10 +2346,17 @@ impl Assembler {
 }
 
 impl Assembler {
-  fn parse_value(to_parse: &str) -> Option<Vec<Byte>> {
+  fn parse_value(&self, to_parse: &str) -> Option<Vec<Byte>> {
     let mut result: Vec<Byte> = Vec::new();
     let to_compare = to_parse.trim();
 
+    if let Some(constant) = self.constants.get(to_compare) {
+      if constant.expression.is_some() {
+        return None;
+      }
+      return Some(constant.value.clone());
+    }
+
     if to_compare.starts_with("0x") {
       match to_compare.len() - 2 {
         2 => match u8::from_str_radix(
@@ -421,23 +2405,33 @@ impl Assembler {
       }
     }
 
-    if to_compare.starts_with(":") {
+    let is_label: bool = match to_compare.strip_prefix(':') {
+      Some(name) => Assembler::is_anonymous_reference(name) || !name.contains(|chr| "+-*/%()".contains(chr)),
+      None => false,
+    };
+    if is_label {
       result.push(Byte {
         byte_type: ByteType::Label,
         byte_value: 0,
         byte_label: to_compare.chars().skip(1).collect::<String>(),
         byte_attached_labels: vec![],
+        byte_line: 0,
+        byte_span: (0, 0),
+        byte_expression: None,
       });
       result.push(Byte {
         byte_type: ByteType::LabelPadding,
         byte_value: 0,
         byte_label: to_compare.chars().skip(1).collect::<String>(),
         byte_attached_labels: vec![],
+        byte_line: 0,
+        byte_span: (0, 0),
+        byte_expression: None,
       });
     }
 
     if to_compare.starts_with("'") && to_compare.ends_with("'") && to_compare.len() == 3 {
-      result.push(Byte::from_u8(to_compare.as_bytes()[1] as u8));
+      result.push(Byte::from_u8(to_compare.as_bytes()[1]));
     }
 
     if to_compare.starts_with("\"") && to_compare.ends_with("\"") && to_compare.len() > 2 {
@@ -452,13 +2446,13 @@ impl Assembler {
           result.push(Byte::from_u8(0));
         } else if chr == 'n' && escape {
           escape = false;
-          result.push(Byte::from_u8('\n' as u8));
+          result.push(Byte::from_u8(b'\n'));
         } else if chr == 'r' && escape {
           escape = false;
-          result.push(Byte::from_u8('\r' as u8));
+          result.push(Byte::from_u8(b'\r'));
         } else if chr == 't' && escape {
           escape = false;
-          result.push(Byte::from_u8('\t' as u8));
+          result.push(Byte::from_u8(b'\t'));
         } else if chr == '\\' {
           if escape {
             result.push(Byte::from_u8(chr as u8));
@@ -476,7 +2470,7 @@ impl Assembler {
       }
     }
 
-    if result.len() == 0 {
+    if result.is_empty() {
       return None;
     }
 
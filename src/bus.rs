@@ -0,0 +1,121 @@
+//! Module for the memory bus, which lets devices claim ranges of addresses so that loads and stores
+//! there reach the device instead of RAM
+//!
+//! Which 256 byte pages have a device in them is kept in a table, so accesses to the rest of memory
+//! only pay for one lookup before going straight to RAM.
+
+use interrupts::InterruptController;
+
+/// A peripheral mapped into memory, addresses given to it are offsets from the start of its range
+pub trait Device {
+  fn read(&mut self, offset: u16) -> u8;
+
+  fn write(&mut self, offset: u16, value: u8);
+
+  /// Called after every instruction with the number of clock cycles it took, the device can raise
+  /// interrupt lines through the controller
+  fn tick(&mut self, _cycles: u64, _interrupts: &mut InterruptController) {}
+}
+
+struct Mapping {
+  start: u16,
+  length: usize,
+  device: Box<dyn Device>,
+}
+
+impl Mapping {
+  fn contains(&self, address: u16) -> bool {
+    address >= self.start && (address as usize) < self.start as usize + self.length
+  }
+}
+
+pub struct Bus {
+  mappings: Vec<Mapping>,
+  mapped_pages: [bool; 256],
+}
+
+impl Default for Bus {
+  fn default() -> Bus {
+    return Bus::new();
+  }
+}
+
+impl Bus {
+  pub fn new() -> Bus {
+    return Bus {
+      mappings: Vec::new(),
+      mapped_pages: [false; 256],
+    };
+  }
+}
+
+/// Gives a device the addresses from start to start + length, which can't overlap another device
+impl Bus {
+  pub fn map(&mut self, start: u16, length: usize, device: Box<dyn Device>) -> Result<(), String> {
+    let end: usize = start as usize + length;
+    if length == 0 || end > 65536 {
+      return Err(format!(
+        "Bus Error: a device of length {} at 0x{:04x} does not fit in memory",
+        length, start
+      ));
+    }
+    for mapping in self.mappings.iter() {
+      let other_end: usize = mapping.start as usize + mapping.length;
+      if (start as usize) < other_end && (mapping.start as usize) < end {
+        return Err(format!(
+          "Bus Error: device at 0x{:04x}-0x{:04x} overlaps the device at 0x{:04x}-0x{:04x}",
+          start,
+          end - 1,
+          mapping.start,
+          other_end - 1
+        ));
+      }
+    }
+    for page in (start as usize >> 8)..=((end - 1) >> 8) {
+      self.mapped_pages[page] = true;
+    }
+    self.mappings.push(Mapping {
+      start,
+      length,
+      device,
+    });
+    return Ok(());
+  }
+}
+
+impl Bus {
+  fn device_at(&mut self, address: u16) -> Option<&mut Mapping> {
+    if !self.mapped_pages[address as usize >> 8] {
+      return None;
+    }
+    return self.mappings.iter_mut().find(|mapping| mapping.contains(address));
+  }
+}
+
+/// Reads a byte from the device at an address, or from RAM if there isn't one
+impl Bus {
+  pub fn read(&mut self, memory: &[u8; 65536], address: u16) -> u8 {
+    match self.device_at(address) {
+      Some(mapping) => mapping.device.read(address - mapping.start),
+      None => memory[address as usize],
+    }
+  }
+}
+
+/// Writes a byte to the device at an address, or to RAM if there isn't one
+impl Bus {
+  pub fn write(&mut self, memory: &mut [u8; 65536], address: u16, value: u8) {
+    match self.device_at(address) {
+      Some(mapping) => mapping.device.write(address - mapping.start, value),
+      None => memory[address as usize] = value,
+    }
+  }
+}
+
+impl Bus {
+  pub fn tick(&mut self, cycles: u64, interrupts: &mut InterruptController) {
+    for mapping in self.mappings.iter_mut() {
+      mapping.device.tick(cycles, interrupts);
+    }
+  }
+}
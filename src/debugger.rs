@@ -0,0 +1,405 @@
+//! Interactive debugger for stepping through programs on the Rusty Jello machine
+
+use rusty_jello::instructions;
+use rusty_jello::{InputPump, Machine, SourceMap, StepResult};
+use std::io;
+use std::io::prelude::*;
+
+const HELP: &str = "Commands:
+  step [count] (s)       Execute one (or count) instructions
+  next (n)               Execute one instruction, stepping over calls
+  continue (c)           Run until a breakpoint or halt
+  finish (f)             Run until the current call returns
+  break LOCATION (b)     Set a breakpoint, LOCATION is an address or :LABEL
+  delete LOCATION (d)    Remove a breakpoint
+  breakpoints (bl)       List breakpoints
+  where (w)              Show the current instruction
+  regs (r)               Show accumulator, instruction pointer and registers
+  stack (st)             Show the stack and instruction pointer stack
+  flags (fl)             Show the flags
+  mem LOCATION [LENGTH]  Show memory (default length 16)
+  set NAME VALUE         Set acc, ip, r0-r3 or a flag (halt, carry, overflow, test, interrupt)
+  poke LOCATION BYTE...  Write bytes to memory
+  push VALUE             Push a value onto the stack
+  pop                    Pop a value off the stack
+  symbols                List labels
+  quit (q)               Exit the debugger
+An empty line repeats the previous command.";
+
+pub struct Debugger {
+  source_map: SourceMap,
+  output_line_open: bool,
+}
+
+impl Debugger {
+  pub fn new(source_map: SourceMap) -> Debugger {
+    return Debugger {
+      source_map,
+      output_line_open: false,
+    };
+  }
+}
+
+impl Debugger {
+  pub fn run(&mut self, machine: &mut Machine, input_pump: &mut InputPump) {
+    let stdin = io::stdin();
+    let mut last_command: String = String::new();
+
+    println!("Rusty Jello debugger, type \"help\" for a list of commands");
+    println!("{}", self.format_location(machine));
+
+    loop {
+      print!("(jello) ");
+      io::stdout().flush().unwrap();
+
+      let mut line: String = String::new();
+      match stdin.lock().read_line(&mut line) {
+        Ok(0) => {
+          println!();
+          return;
+        }
+        Ok(..) => {}
+        Err(err) => {
+          println!("Error reading command, {:?}", err);
+          return;
+        }
+      }
+
+      if line.trim().is_empty() {
+        line = last_command.clone();
+      } else {
+        last_command = line.clone();
+      }
+
+      let words: Vec<&str> = line.split_whitespace().collect();
+      if words.is_empty() {
+        continue;
+      }
+
+      match words[0].to_lowercase().as_str() {
+        "help" | "h" => println!("{}", HELP),
+        "quit" | "q" => return,
+        "step" | "s" => {
+          let mut count: usize = 1;
+          if words.len() > 1 {
+            match Debugger::parse_value(words[1]) {
+              Some(value) => count = value as usize,
+              None => {
+                println!("Invalid step count \"{}\"", words[1]);
+                continue;
+              }
+            }
+          }
+          for _ in 0..count {
+            if !self.step(machine, input_pump) {
+              break;
+            }
+          }
+          self.report_stop(machine);
+        }
+        "next" | "n" => {
+          let depth: usize = machine.instruction_pointer_stack.depth();
+          let opcode: u8 = machine.memory[machine.instruction_pointer as usize];
+          let is_call: bool = match instructions::find_inst_by_opcode(&opcode) {
+            Some(inst) => inst.inst == "CALL" || inst.inst == "CALLI",
+            None => false,
+          };
+          if self.step(machine, input_pump) && is_call {
+            self.run_until(machine, input_pump, |machine: &Machine| {
+              machine.instruction_pointer_stack.depth() <= depth
+            });
+          }
+          self.report_stop(machine);
+        }
+        "continue" | "c" => {
+          self.run_until(machine, input_pump, |_: &Machine| false);
+          self.report_stop(machine);
+        }
+        "finish" | "f" => {
+          let depth: usize = machine.instruction_pointer_stack.depth();
+          if depth == 0 {
+            println!("Not inside a call");
+            continue;
+          }
+          self.run_until(machine, input_pump, |machine: &Machine| {
+            machine.instruction_pointer_stack.depth() < depth
+          });
+          self.report_stop(machine);
+        }
+        "break" | "b" => {
+          if words.len() != 2 {
+            println!("Usage: break LOCATION");
+            continue;
+          }
+          match self.parse_location(words[1]) {
+            Some(address) => {
+              if !machine.breakpoints.contains(&address) {
+                machine.breakpoints.push(address);
+              }
+              println!("Breakpoint set at {}", self.source_map.describe_address(address));
+            }
+            None => println!("Unknown location \"{}\"", words[1]),
+          }
+        }
+        "delete" | "d" => {
+          if words.len() != 2 {
+            println!("Usage: delete LOCATION");
+            continue;
+          }
+          match self.parse_location(words[1]) {
+            Some(address) => {
+              if machine.breakpoints.contains(&address) {
+                machine.breakpoints.retain(|breakpoint| *breakpoint != address);
+                println!("Breakpoint removed from {}", self.source_map.describe_address(address));
+              } else {
+                println!("No breakpoint at {}", self.source_map.describe_address(address));
+              }
+            }
+            None => println!("Unknown location \"{}\"", words[1]),
+          }
+        }
+        "breakpoints" | "bl" => {
+          if machine.breakpoints.is_empty() {
+            println!("No breakpoints set");
+          }
+          for breakpoint in machine.breakpoints.iter() {
+            println!("  {}", self.source_map.describe_address(*breakpoint));
+          }
+        }
+        "where" | "w" => println!("{}", self.format_location(machine)),
+        "regs" | "r" => println!(
+          "acc: {:04x}, ip: {:04x}, r0: {:04x}, r1: {:04x}, r2: {:04x}, r3: {:04x}",
+          machine.accumulator,
+          machine.instruction_pointer,
+          machine.registers[0],
+          machine.registers[1],
+          machine.registers[2],
+          machine.registers[3]
+        ),
+        "stack" | "st" => {
+          println!("stack: {:?}", machine.stack);
+          println!("ip_stack: {:?}", machine.instruction_pointer_stack);
+        }
+        "flags" | "fl" => println!("flags: {:?}", machine.flags),
+        "mem" | "x" => {
+          if words.len() < 2 || words.len() > 3 {
+            println!("Usage: mem LOCATION [LENGTH]");
+            continue;
+          }
+          let address: u16 = match self.parse_location(words[1]) {
+            Some(location) => location,
+            None => {
+              println!("Unknown location \"{}\"", words[1]);
+              continue;
+            }
+          };
+          let mut length: usize = 16;
+          if words.len() == 3 {
+            match Debugger::parse_value(words[2]) {
+              Some(value) => length = value as usize,
+              None => {
+                println!("Invalid length \"{}\"", words[2]);
+                continue;
+              }
+            }
+          }
+          print!("{}", Debugger::format_memory(machine, address, length));
+        }
+        "set" => {
+          if words.len() != 3 {
+            println!("Usage: set NAME VALUE");
+            continue;
+          }
+          let value: u16 = match self.parse_location(words[2]) {
+            Some(parsed) => parsed,
+            None => {
+              println!("Invalid value \"{}\"", words[2]);
+              continue;
+            }
+          };
+          match words[1].to_lowercase().as_str() {
+            "acc" => machine.accumulator = value,
+            "ip" => machine.instruction_pointer = value,
+            "r0" => machine.registers[0] = value,
+            "r1" => machine.registers[1] = value,
+            "r2" => machine.registers[2] = value,
+            "r3" => machine.registers[3] = value,
+            "halt" => {
+              machine.flags.halt = value != 0;
+              if !machine.flags.halt {
+                machine.fault = None;
+              }
+            }
+            "carry" => machine.flags.carry = value != 0,
+            "overflow" => machine.flags.overflow = value != 0,
+            "test" => machine.flags.test = value != 0,
+            "interrupt" => machine.flags.interrupt = value != 0,
+            _ => println!("Unknown name \"{}\"", words[1]),
+          }
+        }
+        "poke" => {
+          if words.len() < 3 {
+            println!("Usage: poke LOCATION BYTE...");
+            continue;
+          }
+          let address: u16 = match self.parse_location(words[1]) {
+            Some(location) => location,
+            None => {
+              println!("Unknown location \"{}\"", words[1]);
+              continue;
+            }
+          };
+          let mut bytes: Vec<u8> = Vec::new();
+          for word in words.iter().skip(2) {
+            match Debugger::parse_value(word) {
+              Some(value) if value <= 0xff => bytes.push(value as u8),
+              _ => {
+                println!("Invalid byte \"{}\"", word);
+                break;
+              }
+            }
+          }
+          if bytes.len() == words.len() - 2 {
+            for (offset, byte) in bytes.iter().enumerate() {
+              machine.write_byte(address.wrapping_add(offset as u16), *byte);
+            }
+          }
+        }
+        "push" => {
+          if words.len() != 2 {
+            println!("Usage: push VALUE");
+            continue;
+          }
+          match self.parse_location(words[1]) {
+            Some(value) => {
+              machine.stack.push(value);
+            }
+            None => println!("Invalid value \"{}\"", words[1]),
+          }
+        }
+        "pop" => println!("{:04x}", machine.stack.pop()),
+        "symbols" => {
+          for symbol in self.source_map.symbols().iter() {
+            println!("  [{:04x}] :{}", symbol.address, symbol.name);
+          }
+        }
+        _ => println!("Unknown command \"{}\", type \"help\" for a list of commands", words[0]),
+      }
+    }
+  }
+}
+
+impl Debugger {
+  /// Steps the machine once and handles its input and output, returning false if the machine
+  /// has halted, hit a breakpoint or run an illegal instruction
+  fn step(&mut self, machine: &mut Machine, input_pump: &mut InputPump) -> bool {
+    // An ignored illegal instruction doesn't move on, so running would otherwise never stop
+    let is_illegal: bool = Debugger::is_illegal(machine);
+    let result: StepResult = machine.step();
+    input_pump.pump(machine);
+    if machine.output_buffer.has_bytes() {
+      let mut stdout = io::stdout();
+      for byte in machine.output_buffer.take_all() {
+        write!(stdout, "{}", byte as char).unwrap();
+        self.output_line_open = byte != b'\n';
+      }
+      stdout.flush().unwrap();
+    }
+    return !is_illegal && matches!(result, StepResult::Executed | StepResult::WaitingForInput);
+  }
+}
+
+impl Debugger {
+  fn is_illegal(machine: &Machine) -> bool {
+    let opcode: u8 = machine.memory[machine.instruction_pointer as usize];
+    return instructions::find_inst_by_opcode(&opcode).is_none();
+  }
+}
+
+impl Debugger {
+  fn run_until<F: Fn(&Machine) -> bool>(
+    &mut self,
+    machine: &mut Machine,
+    input_pump: &mut InputPump,
+    stop: F,
+  ) {
+    while self.step(machine, input_pump) {
+      if stop(machine) {
+        return;
+      }
+    }
+  }
+}
+
+impl Debugger {
+  fn report_stop(&mut self, machine: &mut Machine) {
+    if self.output_line_open {
+      println!();
+      self.output_line_open = false;
+    }
+    if let Some(fault) = machine.fault {
+      println!(
+        "Machine fault, {} (opcode 0x{:02x}) at {}",
+        fault.kind,
+        fault.op_code,
+        self.source_map.describe(fault.address)
+      );
+    } else if machine.flags.halt {
+      println!("Machine halted");
+    } else if machine.breakpoints.contains(&machine.instruction_pointer) {
+      println!("Breakpoint hit");
+    } else if Debugger::is_illegal(machine) {
+      println!("Illegal instruction ignored");
+    }
+    println!("{}", self.format_location(machine));
+  }
+}
+
+impl Debugger {
+  fn format_location(&self, machine: &mut Machine) -> String {
+    let address: u16 = machine.instruction_pointer;
+    return format!("{} {}", self.source_map.describe(address), machine.format_inst());
+  }
+}
+
+/// Parses an address or value, which can be a label (:NAME), hex (0x...) or decimal
+impl Debugger {
+  fn parse_location(&self, to_parse: &str) -> Option<u16> {
+    if let Some(name) = to_parse.strip_prefix(':') {
+      return self.source_map.symbol_address(name);
+    }
+    return Debugger::parse_value(to_parse);
+  }
+}
+
+impl Debugger {
+  fn parse_value(to_parse: &str) -> Option<u16> {
+    if let Some(digits) = to_parse.strip_prefix("0x") {
+      return u16::from_str_radix(digits, 16).ok();
+    }
+    if let Some(digits) = to_parse.strip_prefix("0b") {
+      return u16::from_str_radix(digits, 2).ok();
+    }
+    return to_parse.parse::<u16>().ok();
+  }
+}
+
+impl Debugger {
+  fn format_memory(machine: &mut Machine, address: u16, length: usize) -> String {
+    let mut result: String = String::new();
+    for offset in 0..length {
+      let current: usize = (address as usize + offset) % 65536;
+      if offset % 16 == 0 {
+        if offset != 0 {
+          result += "\n";
+        }
+        result += format!("[{:04x}]", current).as_str();
+      }
+      result += format!(" {:02x}", machine.read_byte(current as u16)).as_str();
+    }
+    if length > 0 {
+      result += "\n";
+    }
+    return result;
+  }
+}
@@ -0,0 +1,216 @@
+//! Module for reporting problems found while assembling, with enough detail to point at the
+//! offending part of the source
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+  Error,
+  Warning,
+}
+
+impl fmt::Display for Severity {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Severity::Error => write!(f, "error"),
+      Severity::Warning => write!(f, "warning"),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiagnosticKind {
+  SyntaxError,
+  ValueError,
+  AmbiguousInput,
+  DataError,
+  UndefinedLabel,
+  UnknownConstant,
+  DanglingLabel,
+  MacroError,
+  IncludeError,
+}
+
+impl fmt::Display for DiagnosticKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DiagnosticKind::SyntaxError => write!(f, "Syntax Error"),
+      DiagnosticKind::ValueError => write!(f, "Value Error"),
+      DiagnosticKind::AmbiguousInput => write!(f, "Ambiguous Input Error"),
+      DiagnosticKind::DataError => write!(f, "Data Error"),
+      DiagnosticKind::UndefinedLabel => write!(f, "Undefined Label Error"),
+      DiagnosticKind::UnknownConstant => write!(f, "Unknown Constant Error"),
+      DiagnosticKind::DanglingLabel => write!(f, "Dangling Label"),
+      DiagnosticKind::MacroError => write!(f, "Macro Error"),
+      DiagnosticKind::IncludeError => write!(f, "Include Error"),
+    }
+  }
+}
+
+/// A single problem in the source, the span is the range of columns (counted in characters from
+/// 0, end exclusive) on the line which the problem relates to
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+  pub severity: Severity,
+  pub kind: DiagnosticKind,
+  pub line: usize,
+  pub span: (usize, usize),
+  pub message: String,
+  pub hint: Option<String>,
+  pub source_line: String,
+  pub file: Option<String>,
+  pub notes: Vec<Note>,
+}
+
+/// Another place in the source which is relevant to a diagnostic, such as the invocation of the
+/// macro the problem was found in
+#[derive(Clone, Debug)]
+pub struct Note {
+  pub message: String,
+  pub file: Option<String>,
+  pub line: usize,
+  pub span: (usize, usize),
+  pub source_line: String,
+}
+
+impl Diagnostic {
+  pub fn error(
+    kind: DiagnosticKind,
+    line: usize,
+    span: (usize, usize),
+    message: String,
+    source_line: &str,
+  ) -> Diagnostic {
+    return Diagnostic {
+      severity: Severity::Error,
+      kind,
+      line,
+      span,
+      message,
+      hint: None,
+      source_line: source_line.to_string(),
+      file: None,
+      notes: Vec::new(),
+    };
+  }
+}
+
+impl Diagnostic {
+  pub fn warning(
+    kind: DiagnosticKind,
+    line: usize,
+    span: (usize, usize),
+    message: String,
+    source_line: &str,
+  ) -> Diagnostic {
+    let mut result: Diagnostic = Diagnostic::error(kind, line, span, message, source_line);
+    result.severity = Severity::Warning;
+    return result;
+  }
+}
+
+impl Diagnostic {
+  pub fn with_hint(mut self, hint: String) -> Diagnostic {
+    self.hint = Some(hint);
+    return self;
+  }
+}
+
+impl Diagnostic {
+  pub fn with_note(mut self, note: Note) -> Diagnostic {
+    self.notes.push(note);
+    return self;
+  }
+}
+
+impl Diagnostic {
+  pub fn is_error(&self) -> bool {
+    self.severity == Severity::Error
+  }
+}
+
+/// Renders the diagnostic with the offending source line and a caret under the span, followed by
+/// any notes in the same form
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut gutter_width: usize = self.line.to_string().len();
+    for note in self.notes.iter() {
+      gutter_width = gutter_width.max(note.line.to_string().len());
+    }
+
+    writeln!(f, "{}: {} [{}]", self.severity, self.message, self.kind)?;
+    write_snippet(f, gutter_width, &self.file, self.line, self.span, self.source_line.as_str())?;
+    if let Some(ref hint) = self.hint {
+      write!(f, "\n{} = hint: {}", " ".repeat(gutter_width), hint)?;
+    }
+    for note in self.notes.iter() {
+      writeln!(f, "\nnote: {}", note.message)?;
+      write_snippet(f, gutter_width, &note.file, note.line, note.span, note.source_line.as_str())?;
+    }
+    return Ok(());
+  }
+}
+
+fn write_snippet(
+  f: &mut fmt::Formatter,
+  gutter_width: usize,
+  file: &Option<String>,
+  line: usize,
+  span: (usize, usize),
+  source_line: &str,
+) -> fmt::Result {
+  let gutter: String = " ".repeat(gutter_width);
+
+  let mut underline: String = String::new();
+  for (column, chr) in source_line.chars().enumerate() {
+    if column >= span.0 {
+      break;
+    }
+    underline.push(if chr == '\t' { '\t' } else { ' ' });
+  }
+  let span_length: usize = if span.1 > span.0 { span.1 - span.0 } else { 1 };
+  underline += "^".repeat(span_length).as_str();
+
+  match *file {
+    Some(ref file) => writeln!(f, "{}--> {}, line {}, column {}", gutter, file, line, span.0 + 1)?,
+    None => writeln!(f, "{}--> line {}, column {}", gutter, line, span.0 + 1)?,
+  }
+  writeln!(f, "{} |", gutter)?;
+  writeln!(f, "{:>width$} | {}", line, source_line, width = gutter_width)?;
+  write!(f, "{} | {}", gutter, underline)
+}
+
+/// Finds the candidate closest to a name (ignoring case) for "did you mean" hints, as long as it
+/// is close enough to plausibly be a typo
+pub fn closest_match<'x, I: Iterator<Item = &'x str>>(name: &str, candidates: I) -> Option<&'x str> {
+  let name: String = name.to_uppercase();
+  let mut best: Option<(&str, usize)> = None;
+  for candidate in candidates {
+    let distance: usize = edit_distance(name.as_str(), candidate.to_uppercase().as_str());
+    match best {
+      Some((_, best_distance)) if best_distance <= distance => {}
+      _ => best = Some((candidate, distance)),
+    }
+  }
+  match best {
+    Some((candidate, distance)) if distance <= 1 + name.len() / 3 => Some(candidate),
+    _ => None,
+  }
+}
+
+fn edit_distance(from: &str, to: &str) -> usize {
+  let from: Vec<char> = from.chars().collect();
+  let to: Vec<char> = to.chars().collect();
+  let mut previous: Vec<usize> = (0..to.len() + 1).collect();
+  for (from_index, from_char) in from.iter().enumerate() {
+    let mut current: Vec<usize> = vec![from_index + 1];
+    for to_index in 0..to.len() {
+      let substitution: usize = previous[to_index] + if *from_char == to[to_index] { 0 } else { 1 };
+      let insertion: usize = current[to_index] + 1;
+      let deletion: usize = previous[to_index + 1] + 1;
+      current.push(substitution.min(insertion).min(deletion));
+    }
+    previous = current;
+  }
+  return previous[to.len()];
+}
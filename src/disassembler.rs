@@ -0,0 +1,176 @@
+//! Module for turning Rusty Jello byte code back into assembly which can be re-assembled
+
+use instructions;
+use instructions::Instruction;
+use std::collections::HashSet;
+
+/// Instructions which take an immediate address to jump to, these get labels for their targets
+const JUMP_INSTRUCTIONS: [&str; 6] = ["JMPI", "JMPIG", "JMPIL", "JMPIE", "JMPIT", "CALLI"];
+
+struct DecodedInstruction {
+  address: usize,
+  inst: &'static Instruction<'static>,
+  operands: Vec<u16>,
+}
+
+pub struct Disassembler {
+  bytecode: Vec<u8>,
+}
+
+impl Default for Disassembler {
+  fn default() -> Disassembler {
+    return Disassembler::new();
+  }
+}
+
+impl Disassembler {
+  pub fn new() -> Disassembler {
+    return Disassembler {
+      bytecode: Vec::new(),
+    };
+  }
+}
+
+impl Disassembler {
+  pub fn add_bytes(&mut self, new_bytes: &[u8]) {
+    self.bytecode.extend_from_slice(new_bytes);
+  }
+}
+
+impl Disassembler {
+  pub fn disassemble(&self) -> String {
+    let (decoded, code_end) = self.decode_instructions();
+
+    let mut instruction_starts: HashSet<usize> = HashSet::new();
+    for decoded_inst in decoded.iter() {
+      instruction_starts.insert(decoded_inst.address);
+    }
+
+    let mut jump_targets: HashSet<usize> = HashSet::new();
+    for decoded_inst in decoded.iter() {
+      if JUMP_INSTRUCTIONS.contains(&decoded_inst.inst.inst) {
+        let target: usize = decoded_inst.operands[0] as usize;
+        if instruction_starts.contains(&target) {
+          jump_targets.insert(target);
+        }
+      }
+    }
+
+    let mut result: String = String::new();
+    result += "#Disassembled by Rusty Jello\n";
+
+    for decoded_inst in decoded.iter() {
+      if jump_targets.contains(&decoded_inst.address) {
+        result += format!("{}\n", Disassembler::label_for(decoded_inst.address)).as_str();
+      }
+      result += decoded_inst.inst.inst;
+      for operand in decoded_inst.operands.iter() {
+        if JUMP_INSTRUCTIONS.contains(&decoded_inst.inst.inst)
+          && jump_targets.contains(&(*operand as usize))
+        {
+          result += format!(" {}", Disassembler::label_for(*operand as usize)).as_str();
+        } else if decoded_inst.inst.bytes_per_arg == 1 {
+          result += format!(" 0x{:02x}", operand).as_str();
+        } else {
+          result += format!(" 0x{:04x}", operand).as_str();
+        }
+      }
+      result += "\n";
+    }
+
+    result += self.format_data(code_end).as_str();
+
+    return result;
+  }
+}
+
+impl Disassembler {
+  /// Decodes instructions from address 0 until a byte which can't be decoded, returning the
+  /// instructions and the address at which decoding stopped
+  fn decode_instructions(&self) -> (Vec<DecodedInstruction>, usize) {
+    let mut result: Vec<DecodedInstruction> = Vec::new();
+    let mut address: usize = 0;
+    while address < self.bytecode.len() {
+      let inst: &Instruction = match instructions::find_inst_by_opcode(&self.bytecode[address]) {
+        Some(i) => i,
+        None => break,
+      };
+      let bytes_per_arg: usize = inst.bytes_per_arg as usize;
+      let inst_size: usize = 1 + bytes_per_arg * inst.num_args as usize;
+      if address + inst_size > self.bytecode.len() {
+        break;
+      }
+      let mut operands: Vec<u16> = Vec::new();
+      for arg in 0..inst.num_args as usize {
+        let mut operand: u16 = 0;
+        for byte in 0..bytes_per_arg {
+          operand |= (self.bytecode[address + 1 + arg * bytes_per_arg + byte] as u16) << (8 * byte);
+        }
+        operands.push(operand);
+      }
+      result.push(DecodedInstruction {
+        address,
+        inst,
+        operands,
+      });
+      address += inst_size;
+    }
+    return (result, address);
+  }
+}
+
+impl Disassembler {
+  /// Formats everything from the given address onwards as data directives, the assembler zero
+  /// fills gaps between directives so only non-zero runs (and the final byte) are emitted
+  fn format_data(&self, start: usize) -> String {
+    let mut result: String = String::new();
+    let mut address: usize = start;
+    while address < self.bytecode.len() {
+      let byte: u8 = self.bytecode[address];
+      if byte == 0 && address + 1 != self.bytecode.len() {
+        address += 1;
+        continue;
+      }
+      let mut string_end: usize = address;
+      while string_end < self.bytecode.len()
+        && Disassembler::is_string_safe(self.bytecode[string_end])
+      {
+        string_end += 1;
+      }
+      if string_end - address > 1 {
+        let mut text: String = String::new();
+        for string_byte in self.bytecode[address..string_end].iter() {
+          match *string_byte {
+            b'\n' => text += "\\n",
+            b'\r' => text += "\\r",
+            b'\t' => text += "\\t",
+            other => text.push(other as char),
+          }
+        }
+        result += format!(".DATA 0x{:04x} \"{}\"\n", address, text).as_str();
+        address = string_end;
+      } else {
+        result += format!(".DATA 0x{:04x} 0x{:02x}\n", address, byte).as_str();
+        address += 1;
+      }
+    }
+    return result;
+  }
+}
+
+impl Disassembler {
+  /// Checks whether a byte can be written inside a quoted string and read back unchanged
+  fn is_string_safe(byte: u8) -> bool {
+    match byte {
+      b'"' | b'\'' | b'\\' => false,
+      b'\n' | b'\r' | b'\t' => true,
+      _ => (0x20..0x7f).contains(&byte),
+    }
+  }
+}
+
+impl Disassembler {
+  fn label_for(address: usize) -> String {
+    return format!(":L{:04x}", address);
+  }
+}
@@ -0,0 +1,289 @@
+//! Module for parsing and evaluating arithmetic expressions in assembler operands
+//!
+//! Expressions are written without spaces and support, from loosest to tightest binding:
+//!
+//!   - `+` and `-`
+//!   - `*`, `/` and `%`
+//!   - unary `-`, `<` (low byte) and `>` (high byte)
+//!   - parentheses, decimal, hex (`0x`) and binary (`0b`) numbers, characters (`'a'`), labels
+//!     (`:NAME`, `:.LOCAL` or anonymous `:+` and `:-`) and constants (`NAME`)
+
+#[derive(Clone, Debug)]
+pub enum Expression {
+  Number(i64),
+  Label(String),
+  Negate(Box<Expression>),
+  LowByte(Box<Expression>),
+  HighByte(Box<Expression>),
+  Binary(char, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+  /// Evaluates the expression, looking up label addresses with the given function
+  pub fn evaluate(&self, lookup_label: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+    match *self {
+      Expression::Number(value) => Ok(value),
+      Expression::Label(ref name) => match lookup_label(name) {
+        Some(address) => Ok(address),
+        None => Err(format!("undefined label \"{}\"", name)),
+      },
+      Expression::Negate(ref inner) => inner.evaluate(lookup_label)?.checked_neg().ok_or_else(overflow),
+      Expression::LowByte(ref inner) => Ok(inner.evaluate(lookup_label)? & 0xff),
+      Expression::HighByte(ref inner) => Ok((inner.evaluate(lookup_label)? >> 8) & 0xff),
+      Expression::Binary(operator, ref left, ref right) => {
+        let left: i64 = left.evaluate(lookup_label)?;
+        let right: i64 = right.evaluate(lookup_label)?;
+        match operator {
+          '+' => left.checked_add(right).ok_or_else(overflow),
+          '-' => left.checked_sub(right).ok_or_else(overflow),
+          '*' => left.checked_mul(right).ok_or_else(overflow),
+          '/' | '%' if right == 0 => Err("division by zero".to_string()),
+          '/' => left.checked_div(right).ok_or_else(overflow),
+          '%' => left.checked_rem(right).ok_or_else(overflow),
+          _ => Err(format!("unknown operator '{}'", operator)),
+        }
+      }
+    }
+  }
+}
+
+fn overflow() -> String {
+  return "expression overflows".to_string();
+}
+
+/// Names of every label the expression refers to
+impl Expression {
+  pub fn labels(&self) -> Vec<String> {
+    match *self {
+      Expression::Number(..) => vec![],
+      Expression::Label(ref name) => vec![name.to_string()],
+      Expression::Negate(ref inner) | Expression::LowByte(ref inner) | Expression::HighByte(ref inner) => {
+        inner.labels()
+      }
+      Expression::Binary(_, ref left, ref right) => {
+        let mut result: Vec<String> = left.labels();
+        result.extend(right.labels());
+        result
+      }
+    }
+  }
+}
+
+/// Copies the expression with every label renamed by the given function
+impl Expression {
+  pub fn map_labels(&self, rename: &dyn Fn(&str) -> String) -> Expression {
+    match *self {
+      Expression::Number(value) => Expression::Number(value),
+      Expression::Label(ref name) => Expression::Label(rename(name)),
+      Expression::Negate(ref inner) => Expression::Negate(Box::new(inner.map_labels(rename))),
+      Expression::LowByte(ref inner) => Expression::LowByte(Box::new(inner.map_labels(rename))),
+      Expression::HighByte(ref inner) => Expression::HighByte(Box::new(inner.map_labels(rename))),
+      Expression::Binary(operator, ref left, ref right) => Expression::Binary(
+        operator,
+        Box::new(left.map_labels(rename)),
+        Box::new(right.map_labels(rename)),
+      ),
+    }
+  }
+}
+
+/// Whether the expression selects a single byte, which makes it a 1 byte value where the width
+/// isn't otherwise known
+impl Expression {
+  pub fn is_byte(&self) -> bool {
+    matches!(*self, Expression::LowByte(..) | Expression::HighByte(..))
+  }
+}
+
+/// Encodes a value as little endian bytes of the given width, negative values are encoded as two's
+/// complement
+pub fn encode(value: i64, width: usize) -> Result<Vec<u8>, String> {
+  let bits: usize = 8 * width;
+  if value >= (1i64 << bits) || value < -(1i64 << (bits - 1)) {
+    return Err(format!("value {} does not fit in {} byte(s)", value, width));
+  }
+  let mut result: Vec<u8> = Vec::new();
+  for index in 0..width {
+    result.push(((value >> (8 * index)) & 0xff) as u8);
+  }
+  return Ok(result);
+}
+
+/// Parses an expression, resolving constant names with the given function
+pub fn parse(
+  text: &str,
+  lookup_constant: &dyn Fn(&str) -> Result<Expression, String>,
+) -> Result<Expression, String> {
+  let mut parser: Parser = Parser {
+    chars: text.chars().collect(),
+    position: 0,
+    lookup_constant,
+  };
+  let result: Expression = parser.parse_sum()?;
+  if parser.position < parser.chars.len() {
+    return Err(format!(
+      "unexpected '{}' at position {}",
+      parser.chars[parser.position],
+      parser.position + 1
+    ));
+  }
+  return Ok(result);
+}
+
+struct Parser<'x> {
+  chars: Vec<char>,
+  position: usize,
+  lookup_constant: &'x dyn Fn(&str) -> Result<Expression, String>,
+}
+
+impl<'x> Parser<'x> {
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.position).cloned()
+  }
+}
+
+impl<'x> Parser<'x> {
+  fn parse_sum(&mut self) -> Result<Expression, String> {
+    let mut result: Expression = self.parse_product()?;
+    while let Some(operator) = self.peek() {
+      if operator != '+' && operator != '-' {
+        break;
+      }
+      self.position += 1;
+      let right: Expression = self.parse_product()?;
+      result = Expression::Binary(operator, Box::new(result), Box::new(right));
+    }
+    return Ok(result);
+  }
+}
+
+impl<'x> Parser<'x> {
+  fn parse_product(&mut self) -> Result<Expression, String> {
+    let mut result: Expression = self.parse_unary()?;
+    while let Some(operator) = self.peek() {
+      if operator != '*' && operator != '/' && operator != '%' {
+        break;
+      }
+      self.position += 1;
+      let right: Expression = self.parse_unary()?;
+      result = Expression::Binary(operator, Box::new(result), Box::new(right));
+    }
+    return Ok(result);
+  }
+}
+
+impl<'x> Parser<'x> {
+  fn parse_unary(&mut self) -> Result<Expression, String> {
+    match self.peek() {
+      Some('-') => {
+        self.position += 1;
+        Ok(Expression::Negate(Box::new(self.parse_unary()?)))
+      }
+      Some('<') => {
+        self.position += 1;
+        Ok(Expression::LowByte(Box::new(self.parse_unary()?)))
+      }
+      Some('>') => {
+        self.position += 1;
+        Ok(Expression::HighByte(Box::new(self.parse_unary()?)))
+      }
+      _ => self.parse_primary(),
+    }
+  }
+}
+
+impl<'x> Parser<'x> {
+  fn parse_primary(&mut self) -> Result<Expression, String> {
+    let start: usize = self.position;
+    match self.peek() {
+      None => Err("expression ended unexpectedly".to_string()),
+      Some('(') => {
+        self.position += 1;
+        let result: Expression = self.parse_sum()?;
+        if self.peek() != Some(')') {
+          return Err(format!("expected ')' at position {}", self.position + 1));
+        }
+        self.position += 1;
+        Ok(result)
+      }
+      Some('\'') => {
+        if self.chars.len() < start + 3 || self.chars[start + 2] != '\'' {
+          return Err(format!("unterminated character at position {}", start + 1));
+        }
+        self.position += 3;
+        Ok(Expression::Number(self.chars[start + 1] as i64))
+      }
+      Some(':') => {
+        self.position += 1;
+        let name: String = match self.peek() {
+          Some(sign) if sign == '+' || sign == '-' => self.take_run(sign),
+          _ => self.take_label_name(),
+        };
+        if name.is_empty() {
+          return Err(format!("missing label name at position {}", start + 1));
+        }
+        Ok(Expression::Label(name))
+      }
+      Some(chr) if chr.is_ascii_digit() => {
+        let word: String = self.take_word();
+        let parsed = if let Some(digits) = word.strip_prefix("0x") {
+          i64::from_str_radix(digits, 16)
+        } else if let Some(digits) = word.strip_prefix("0b") {
+          i64::from_str_radix(digits, 2)
+        } else {
+          word.parse::<i64>()
+        };
+        match parsed {
+          Ok(value) if value <= 0xffff => Ok(Expression::Number(value)),
+          _ => Err(format!("invalid number \"{}\"", word)),
+        }
+      }
+      Some(chr) if chr.is_ascii_alphabetic() || chr == '_' => {
+        let name: String = self.take_word();
+        (self.lookup_constant)(name.as_str())
+      }
+      Some(chr) => Err(format!("unexpected '{}' at position {}", chr, start + 1)),
+    }
+  }
+}
+
+impl<'x> Parser<'x> {
+  fn take_word(&mut self) -> String {
+    let mut result: String = String::new();
+    while let Some(chr) = self.peek() {
+      if !(chr.is_ascii_alphanumeric() || chr == '_') {
+        break;
+      }
+      result.push(chr);
+      self.position += 1;
+    }
+    return result;
+  }
+}
+
+/// Takes a label name, which unlike other words can contain . to refer to local labels
+impl<'x> Parser<'x> {
+  fn take_label_name(&mut self) -> String {
+    let mut result: String = String::new();
+    while let Some(chr) = self.peek() {
+      if !(chr.is_ascii_alphanumeric() || chr == '_' || chr == '.') {
+        break;
+      }
+      result.push(chr);
+      self.position += 1;
+    }
+    return result;
+  }
+}
+
+/// Takes a run of the same character, such as the ++ of an anonymous label reference
+impl<'x> Parser<'x> {
+  fn take_run(&mut self, chr: char) -> String {
+    let mut result: String = String::new();
+    while self.peek() == Some(chr) {
+      result.push(chr);
+      self.position += 1;
+    }
+    return result;
+  }
+}
@@ -0,0 +1,84 @@
+//! Host side input source which feeds the machine's input buffer
+
+use machine::Machine;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+/// Reads from a host source (stdin, a file, ...) on a separate thread so the machine can poll for
+/// input without blocking, and only blocks when the machine is actually waiting on input
+pub struct InputPump {
+  receiver: Receiver<Vec<u8>>,
+  pending: VecDeque<u8>,
+  finished: bool,
+}
+
+impl InputPump {
+  pub fn new<R: Read + Send + 'static>(mut source: R) -> InputPump {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+      let mut chunk: [u8; 256] = [0; 256];
+      loop {
+        match source.read(&mut chunk) {
+          Ok(0) => break,
+          Ok(count) => if sender.send(chunk[..count].to_vec()).is_err() {
+            break;
+          },
+          Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+          Err(..) => break,
+        }
+      }
+    });
+    return InputPump {
+      receiver,
+      pending: VecDeque::new(),
+      finished: false,
+    };
+  }
+}
+
+impl InputPump {
+  fn receive(&mut self, block: bool) {
+    if self.finished {
+      return;
+    }
+    if block {
+      match self.receiver.recv() {
+        Ok(chunk) => self.pending.extend(chunk),
+        Err(..) => self.finished = true,
+      }
+    }
+    loop {
+      match self.receiver.try_recv() {
+        Ok(chunk) => self.pending.extend(chunk),
+        Err(TryRecvError::Empty) => break,
+        Err(TryRecvError::Disconnected) => {
+          self.finished = true;
+          break;
+        }
+      }
+    }
+  }
+}
+
+/// Moves any available input into the machine's input buffer, blocking only when the machine is
+/// waiting for input and none is pending
+impl InputPump {
+  pub fn pump(&mut self, machine: &mut Machine) {
+    let block: bool = machine.waiting_for_input && self.pending.is_empty();
+    self.receive(block);
+    while machine.input_buffer.free_space() > 0 {
+      match self.pending.pop_front() {
+        Some(byte) => {
+          machine.input_buffer.put(byte);
+        }
+        None => break,
+      }
+    }
+    if self.finished && self.pending.is_empty() {
+      machine.input_buffer.close();
+    }
+  }
+}
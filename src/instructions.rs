@@ -1,37 +1,66 @@
 //! Module for storing instruction definitions
-use machine::Machine;
+use machine::{FaultKind, Machine};
+use std::collections::HashMap;
+use std::sync::OnceLock;
 
 #[derive(Clone)]
 pub struct Instruction<'x> {
   pub inst: &'x str,
   pub num_args: u8,
   pub op_code: u8,
-  pub run: &'x Fn(&mut Machine),
+  pub run: &'x (dyn Fn(&mut Machine) + Sync),
   pub bytes_per_arg: u8,
   pub clock_cycles: usize,
 }
 
-pub fn find_inst_by_opcode(op_code: &u8) -> Option<Instruction> {
-  for index in 0..INSTRUCTION_COUNT {
-    if INSTRUCTIONS[index].op_code == *op_code {
-      return Some(INSTRUCTIONS[index].clone());
-    }
+/// Marks an opcode with no instruction in the dispatch table
+const NO_INSTRUCTION: u8 = 0xff;
+
+/// Index into INSTRUCTIONS for every possible opcode, built at compile time so decoding an
+/// instruction doesn't depend on where it is in the list, two instructions sharing an opcode fails
+/// the build
+static DISPATCH_TABLE: [u8; 256] = build_dispatch_table();
+
+const fn build_dispatch_table() -> [u8; 256] {
+  let mut result: [u8; 256] = [NO_INSTRUCTION; 256];
+  let mut index: usize = 0;
+  while index < INSTRUCTION_COUNT {
+    let op_code: usize = INSTRUCTIONS[index].op_code as usize;
+    assert!(result[op_code] == NO_INSTRUCTION, "two instructions share an opcode");
+    result[op_code] = index as u8;
+    index += 1;
   }
-  return None;
+  return result;
 }
 
-pub fn find_inst_by_name(name: &str) -> Option<Instruction> {
-  for index in 0..INSTRUCTION_COUNT {
-    if INSTRUCTIONS[index].inst == name.to_uppercase() {
-      return Some(INSTRUCTIONS[index].clone());
-    }
+/// Index into INSTRUCTIONS for every instruction name, built the first time a name is looked up
+static NAME_TABLE: OnceLock<HashMap<&'static str, usize>> = OnceLock::new();
+
+pub fn find_inst_by_opcode(op_code: &u8) -> Option<&'static Instruction<'static>> {
+  match DISPATCH_TABLE[*op_code as usize] {
+    NO_INSTRUCTION => None,
+    index => Some(&INSTRUCTIONS[index as usize]),
   }
-  return None;
 }
 
-pub const INSTRUCTION_COUNT: usize = 105;
+pub fn find_inst_by_name(name: &str) -> Option<&'static Instruction<'static>> {
+  let names: &HashMap<&'static str, usize> = NAME_TABLE.get_or_init(|| {
+    INSTRUCTIONS
+      .iter()
+      .enumerate()
+      .map(|(index, instruction)| (instruction.inst, index))
+      .collect()
+  });
+  return names.get(name.to_uppercase().as_str()).map(|&index| &INSTRUCTIONS[index]);
+}
+
+pub const INSTRUCTION_COUNT: usize = 113;
 
-pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
+/// Revision of the instruction set, bumped whenever instructions are added or changed so object
+/// files can record which revision they target
+pub const ISA_REVISION: u16 = 3;
+
+pub static INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
   Instruction {
     inst: "NOOP",
     num_args: 0,
@@ -52,6 +81,59 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
     bytes_per_arg: 0,
     clock_cycles: 1,
   },
+  Instruction {
+    inst: "SYSCALL",
+    num_args: 1,
+    op_code: 0x09,
+    run: &|machine: &mut Machine| {
+      let address: u16 = machine.instruction_pointer;
+      let number: u8 = machine.memory[(address as u32 + 1) as u16 as usize];
+      if !machine.call_syscall(number) && !machine.raise_fault(FaultKind::UnknownSyscall) {
+        return;
+      }
+      // The handler may have halted, raised a fault or jumped, otherwise carry on past the SYSCALL
+      if machine.instruction_pointer == address && !machine.flags.halt {
+        machine.instruction_pointer = (address as u32 + 2) as u16;
+      }
+    },
+    bytes_per_arg: 1,
+    clock_cycles: 2,
+  },
+  Instruction {
+    inst: "EI",
+    num_args: 0,
+    op_code: 0x0a,
+    run: &|machine: &mut Machine| {
+      machine.flags.interrupt = true;
+      machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+    },
+    bytes_per_arg: 0,
+    clock_cycles: 1,
+  },
+  Instruction {
+    inst: "DI",
+    num_args: 0,
+    op_code: 0x0b,
+    run: &|machine: &mut Machine| {
+      machine.flags.interrupt = false;
+      machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+    },
+    bytes_per_arg: 0,
+    clock_cycles: 1,
+  },
+  Instruction {
+    inst: "RETI",
+    num_args: 0,
+    op_code: 0x0c,
+    run: &|machine: &mut Machine| {
+      let flags: u16 = machine.instruction_pointer_stack.pop();
+      machine.flags.restore_word(flags);
+      machine.instruction_pointer = machine.instruction_pointer_stack.pop();
+      machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+    },
+    bytes_per_arg: 0,
+    clock_cycles: 2,
+  },
   Instruction {
     inst: "LRI",
     num_args: 1,
@@ -189,7 +271,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
       let carry: bool;
       if result > 65535 {
         carry = true;
-        result = result % 65536;
+        result %= 65536;
       } else {
         carry = false;
       }
@@ -211,7 +293,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
       let carry: bool;
       if result > 65535 {
         carry = true;
-        result = result % 65536;
+        result %= 65536;
       } else {
         carry = false;
       }
@@ -233,7 +315,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
       let carry: bool;
       if result < 0 {
         carry = true;
-        result = 65536 + result;
+        result += 65536;
       } else {
         carry = false;
       }
@@ -256,7 +338,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
       let carry: bool;
       if result < 0 {
         carry = true;
-        result = 65536 + result;
+        result += 65536;
       } else {
         carry = false;
       }
@@ -309,8 +391,16 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
     run: &|machine: &mut Machine| {
       let input1: u16 = machine.stack.pop();
       let input2: u16 = machine.stack.pop();
-      let result: u16 = input1 as u16 / input2 as u16;
-      machine.accumulator = result as u16;
+      if input2 == 0 {
+        if !machine.raise_fault(FaultKind::DivideByZero) {
+          return;
+        }
+        machine.accumulator = 0;
+        machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+        return;
+      }
+      let result: u16 = input1 / input2;
+      machine.accumulator = result;
       machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
     },
     bytes_per_arg: 0,
@@ -331,7 +421,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
       let carry: bool;
       if result > 65535 {
         carry = true;
-        result = result % 65536;
+        result %= 65536;
       } else {
         carry = false;
       }
@@ -357,7 +447,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
       let carry: bool;
       if result < 0 {
         carry = true;
-        result = 65536 + result;
+        result += 65536;
       } else {
         carry = false;
       }
@@ -870,10 +960,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
     op_code: 0x50,
     run: &|machine: &mut Machine| {
       let address: u16 = machine.stack.pop();
-      let part1: u16 = machine.memory[address as usize] as u16;
-      let address2: u16 = ((address as usize + 1) % 65536) as u16;
-      let part2: u16 = (machine.memory[address2 as usize] as u16) << 8;
-      let loaded_data = part1 | part2;
+      let loaded_data: u16 = machine.read_word(address);
       machine.stack.push(loaded_data);
 
       machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
@@ -888,11 +975,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
     run: &|machine: &mut Machine| {
       let address: u16 = machine.stack.pop();
       let data: u16 = machine.stack.pop();
-      let part1: u8 = (data & 0xff) as u8;
-      let part2: u8 = ((data >> 8) & 0xff) as u8;
-      machine.memory[address as usize] = part1;
-      let address2: u16 = ((address as usize + 1) % 65536) as u16;
-      machine.memory[address2 as usize] = part2;
+      machine.write_word(address, data);
 
       machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
     },
@@ -909,10 +992,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
       machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
       let apart2: u16 = (machine.memory[machine.instruction_pointer as usize] as u16) << 8;
       let address = apart1 | apart2;
-      let part1: u16 = machine.memory[address as usize] as u16;
-      let address2: u16 = ((address as usize + 1) % 65536) as u16;
-      let part2: u16 = (machine.memory[address2 as usize] as u16) << 8;
-      let loaded_data = part1 | part2;
+      let loaded_data: u16 = machine.read_word(address);
       machine.stack.push(loaded_data);
 
       machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
@@ -931,11 +1011,7 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
       let apart2: u16 = (machine.memory[machine.instruction_pointer as usize] as u16) << 8;
       let address = apart1 | apart2;
       let data: u16 = machine.stack.pop();
-      let part1: u8 = (data & 0xff) as u8;
-      let part2: u8 = ((data >> 8) & 0xff) as u8;
-      machine.memory[address as usize] = part1;
-      let address2: u16 = ((address as usize + 1) % 65536) as u16;
-      machine.memory[address2 as usize] = part2;
+      machine.write_word(address, data);
 
       machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
     },
@@ -1626,4 +1702,84 @@ pub const INSTRUCTIONS: [Instruction; INSTRUCTION_COUNT] = [
     bytes_per_arg: 0,
     clock_cycles: 1,
   },
+  Instruction {
+    inst: "INP",
+    num_args: 0,
+    op_code: 0xf6,
+    run: &|machine: &mut Machine| {
+      match machine.input_buffer.take() {
+        Some(byte) => {
+          machine.waiting_for_input = false;
+          machine.stack.push(byte as u16);
+          machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+        }
+        None => if machine.input_buffer.is_closed() {
+          machine.waiting_for_input = false;
+          machine.stack.push(0xffff);
+          machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+        } else {
+          machine.waiting_for_input = true;
+        },
+      }
+    },
+    bytes_per_arg: 0,
+    clock_cycles: 1,
+  },
+  Instruction {
+    inst: "INP2",
+    num_args: 0,
+    op_code: 0xf7,
+    run: &|machine: &mut Machine| {
+      if machine.input_buffer.len() >= 2 {
+        let part1: u16 = machine.input_buffer.take().unwrap() as u16;
+        let part2: u16 = (machine.input_buffer.take().unwrap() as u16) << 8;
+        machine.waiting_for_input = false;
+        machine.stack.push(part1 | part2);
+        machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+      } else if machine.input_buffer.is_closed() {
+        machine.input_buffer.clear();
+        machine.waiting_for_input = false;
+        machine.stack.push(0xffff);
+        machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+      } else {
+        machine.waiting_for_input = true;
+      }
+    },
+    bytes_per_arg: 0,
+    clock_cycles: 1,
+  },
+  Instruction {
+    inst: "INPK",
+    num_args: 0,
+    op_code: 0xf8,
+    run: &|machine: &mut Machine| {
+      match machine.input_buffer.peek() {
+        Some(byte) => {
+          machine.waiting_for_input = false;
+          machine.stack.push(byte as u16);
+          machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+        }
+        None => if machine.input_buffer.is_closed() {
+          machine.waiting_for_input = false;
+          machine.stack.push(0xffff);
+          machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+        } else {
+          machine.waiting_for_input = true;
+        },
+      }
+    },
+    bytes_per_arg: 0,
+    clock_cycles: 1,
+  },
+  Instruction {
+    inst: "INPT",
+    num_args: 0,
+    op_code: 0xf9,
+    run: &|machine: &mut Machine| {
+      machine.flags.test = machine.input_buffer.has_bytes();
+      machine.instruction_pointer = (machine.instruction_pointer as u32 + 1) as u16;
+    },
+    bytes_per_arg: 0,
+    clock_cycles: 1,
+  },
 ];
@@ -0,0 +1,78 @@
+//! Module for the interrupt controller, which lets devices and the host interrupt the running
+//! program on one of eight lines
+//!
+//! Each line has a 16-bit handler address in the vector table, line 0 at the start of the table and
+//! line 7 at the end, where 0 means the line has no handler. When more than one line with a handler
+//! is raised the lowest numbered line is handled first.
+
+/// Number of interrupt lines, the vector table has an entry for each
+pub const INTERRUPT_LINES: u8 = 8;
+
+/// Line raised by the machine itself while the input buffer has bytes in it
+pub const SERIAL_INPUT_LINE: u8 = 0;
+
+/// Line the command line tool gives the timer mapped with -timer
+pub const TIMER_LINE: u8 = 1;
+
+/// Where the vector table is unless moved, the last 16 bytes of memory
+pub const DEFAULT_VECTOR_TABLE: u16 = 0xfff0;
+
+pub struct InterruptController {
+  pending: u8,
+  pub vector_table: u16,
+}
+
+impl Default for InterruptController {
+  fn default() -> InterruptController {
+    return InterruptController::new();
+  }
+}
+
+impl InterruptController {
+  pub fn new() -> InterruptController {
+    return InterruptController {
+      pending: 0,
+      vector_table: DEFAULT_VECTOR_TABLE,
+    };
+  }
+}
+
+/// Raises a line, which stays raised until its handler is called or it is cleared. Returns false
+/// if there is no such line
+impl InterruptController {
+  pub fn raise(&mut self, line: u8) -> bool {
+    if line >= INTERRUPT_LINES {
+      return false;
+    }
+    self.pending |= 1 << line;
+    return true;
+  }
+}
+
+impl InterruptController {
+  pub fn clear(&mut self, line: u8) {
+    if line < INTERRUPT_LINES {
+      self.pending &= !(1 << line);
+    }
+  }
+}
+
+impl InterruptController {
+  pub fn is_pending(&self, line: u8) -> bool {
+    return line < INTERRUPT_LINES && self.pending & (1 << line) != 0;
+  }
+}
+
+/// Bit mask of the raised lines, line 0 in the lowest bit
+impl InterruptController {
+  pub fn pending(&self) -> u8 {
+    return self.pending;
+  }
+}
+
+/// Address of the entry in the vector table holding the handler for a line
+impl InterruptController {
+  pub fn vector_address(&self, line: u8) -> u16 {
+    return self.vector_table.wrapping_add(line as u16 * 2);
+  }
+}
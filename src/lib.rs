@@ -0,0 +1,63 @@
+//! Rusty Jello is a simple (and a bit hack-y) language created by Jacob Allen
+//!
+//! The library holds everything needed to assemble, link and run Jello programs, so they can be
+//! embedded in other tools. The `rusty_jello` binary is a command line front end built on it.
+//!
+//! `Machine::step` runs one instruction and reports why it stopped as a `StepResult`, while
+//! `Machine::run_for` and `Machine::run_until` run a budget of clock cycles at a time, so a host
+//! can run the machine in between its own work. `run_until` gives `Stopped` when its condition
+//! holds and `Executed` when the budget runs out. Running out of input gives `WaitingForInput`, so
+//! the host can fill `input_buffer` before carrying on.
+//!
+//! Jello code calls out to the host with `SYSCALL n`, which runs the handler registered for `n`
+//! with `Machine::register_syscall`. Handlers get the machine to take their arguments from and
+//! leave their results in.
+//!
+//! Peripherals implement the `Device` trait and are mapped into memory with `Machine::map_device`,
+//! after which loads and stores in their range reach the device instead of RAM.
+//!
+//! Devices raise interrupt lines through the `InterruptController` when they tick, and the host can
+//! raise them with `Machine::raise_interrupt`. Once a program has enabled interrupts with `EI`, the
+//! machine calls the handler for a raised line from the vector table at the end of a step.
+//!
+//! ```
+//! extern crate rusty_jello;
+//!
+//! use rusty_jello::{Assembler, Machine, StepResult};
+//!
+//! let mut assembler: Assembler = Assembler::new();
+//! assembler.add_string("PRNI 'J'\nHALT");
+//! let object = assembler.assemble_object().unwrap();
+//!
+//! let mut machine: Machine = Machine::new();
+//! object.load_into(&mut machine).unwrap();
+//! assert_eq!(machine.run_for(1000), StepResult::Halted);
+//! assert_eq!(machine.output_buffer.take_all(), vec![b'J']);
+//! ```
+
+pub mod assembler;
+pub mod bus;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod expression;
+pub mod input;
+pub mod instructions;
+pub mod interrupts;
+pub mod linker;
+pub mod machine;
+pub mod object_file;
+pub mod source_map;
+pub mod timer;
+
+pub use assembler::Assembler;
+pub use bus::Device;
+pub use diagnostic::Diagnostic;
+pub use disassembler::Disassembler;
+pub use input::InputPump;
+pub use instructions::{find_inst_by_name, find_inst_by_opcode, Instruction, INSTRUCTIONS};
+pub use interrupts::InterruptController;
+pub use linker::Linker;
+pub use machine::{Fault, FaultKind, FaultPolicy, Machine, SerialBuffer, Stack, StepResult, Syscall};
+pub use object_file::ObjectFile;
+pub use source_map::SourceMap;
+pub use timer::Timer;
@@ -0,0 +1,355 @@
+//! Module for linking several object files into one runnable program
+//!
+//! Objects are placed one after another starting at the origin, unless they are given an address
+//! of their own. Libraries are objects which are only placed if they export a symbol which one of
+//! the placed objects imports. The entry point of the result is the entry point of the first
+//! object.
+//!
+//! A linker script lists objects to place in order, one per line as a path optionally followed by
+//! an address, paths are relative to the script and lines starting with # are comments. A line
+//! `ENTRY NAME` makes the exported symbol NAME the entry point instead:
+//!
+//! ```text
+//! # Print library at the start of memory, the main program straight after it
+//! lib/print.jo 0x0000
+//! main.jo
+//! ENTRY MAIN
+//! ```
+
+use object_file::{LineEntry, ObjectFile, Segment, Symbol};
+use std::path::Path;
+
+struct LinkInput {
+  name: String,
+  object: ObjectFile,
+  address: Option<u16>,
+}
+
+pub struct Linker {
+  objects: Vec<LinkInput>,
+  libraries: Vec<LinkInput>,
+  origin: u16,
+  entry: Option<String>,
+}
+
+/// The objects a linker script places, along with the entry point it names
+pub struct LinkerScript {
+  pub placements: Vec<(String, Option<u16>)>,
+  pub entry: Option<String>,
+}
+
+impl Default for Linker {
+  fn default() -> Linker {
+    return Linker::new();
+  }
+}
+
+impl Linker {
+  pub fn new() -> Linker {
+    return Linker {
+      objects: Vec::new(),
+      libraries: Vec::new(),
+      origin: 0,
+      entry: None,
+    };
+  }
+}
+
+/// Adds an object which is always placed, at the given address or after the previous object
+impl Linker {
+  pub fn add_object(&mut self, name: &str, object: ObjectFile, address: Option<u16>) {
+    self.objects.push(LinkInput {
+      name: name.to_string(),
+      object,
+      address,
+    });
+  }
+}
+
+/// Adds an object which is only placed if it is needed to resolve an import
+impl Linker {
+  pub fn add_library(&mut self, name: &str, object: ObjectFile) {
+    self.libraries.push(LinkInput {
+      name: name.to_string(),
+      object,
+      address: None,
+    });
+  }
+}
+
+/// Sets where the first object without an address of its own is placed
+impl Linker {
+  pub fn set_origin(&mut self, origin: u16) {
+    self.origin = origin;
+  }
+}
+
+/// Makes an exported symbol the entry point, rather than the entry point of the first object
+impl Linker {
+  pub fn set_entry(&mut self, name: &str) {
+    self.entry = Some(name.to_string());
+  }
+}
+
+impl Linker {
+  pub fn link(&self) -> Result<ObjectFile, Vec<String>> {
+    let mut errors: Vec<String> = Vec::new();
+    let mut placed: Vec<&LinkInput> = self.objects.iter().collect();
+    let mut unused_libraries: Vec<&LinkInput> = self.libraries.iter().collect();
+
+    loop {
+      let missing: Vec<String> = Linker::missing_symbols(&placed)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+      let needed: Option<usize> = unused_libraries.iter().position(|library| {
+        library.object.exports.iter().any(|export| missing.contains(&export.name))
+      });
+      match needed {
+        Some(index) => placed.push(unused_libraries.remove(index)),
+        None => break,
+      }
+    }
+
+    for (name, importer) in Linker::missing_symbols(&placed) {
+      errors.push(format!(
+        "Link Error: undefined symbol \"{}\" imported by {}",
+        name, importer
+      ));
+    }
+
+    let mut bases: Vec<u16> = Vec::new();
+    let mut location: usize = self.origin as usize;
+    let mut ranges: Vec<(usize, usize, &str)> = Vec::new();
+    for input in placed.iter() {
+      let base: usize = match input.address {
+        Some(address) => address as usize,
+        None => location,
+      };
+      let end: usize = base + input.object.extent();
+      if end > 65536 {
+        errors.push(format!(
+          "Link Error: {} placed at 0x{:04x} does not fit in memory",
+          input.name, base
+        ));
+      }
+      if base != 0 {
+        if let Some(address) = input.object.fixed.first() {
+          errors.push(format!(
+            "Link Error: {} can only be placed at 0x0000, the value at 0x{:04x} depends on its address in a way that can't be relocated",
+            input.name, address
+          ));
+        }
+      }
+      // Ranges are shown with inclusive ends like the bus shows devices, an empty object takes no
+      // bytes so it can't overlap anything
+      for &(start, other_end, other_name) in ranges.iter() {
+        if base < end && start < other_end && base < other_end && start < end {
+          errors.push(format!(
+            "Link Error: {} (0x{:04x}-0x{:04x}) overlaps {} (0x{:04x}-0x{:04x})",
+            input.name,
+            base,
+            end - 1,
+            other_name,
+            start,
+            other_end - 1
+          ));
+        }
+      }
+      ranges.push((base, end, input.name.as_str()));
+      bases.push(base as u16);
+      location = end;
+    }
+
+    let mut exports: Vec<(Symbol, &str)> = Vec::new();
+    for (input, base) in placed.iter().zip(bases.iter()) {
+      for export in input.object.exports.iter() {
+        match exports.iter().find(|(existing, _)| existing.name == export.name) {
+          Some(&(_, other_name)) => errors.push(format!(
+            "Link Error: duplicate symbol \"{}\" exported by {} and {}",
+            export.name, other_name, input.name
+          )),
+          None => exports.push((
+            Symbol {
+              name: export.name.clone(),
+              address: export.address.wrapping_add(*base),
+            },
+            input.name.as_str(),
+          )),
+        }
+      }
+    }
+
+    let mut result: ObjectFile = ObjectFile::new();
+    match self.entry {
+      Some(ref name) => match exports.iter().find(|(export, _)| export.name == *name) {
+        Some((export, _)) => result.entry_point = export.address,
+        None => errors.push(format!("Link Error: entry point \"{}\" is not exported by any object", name)),
+      },
+      None => {
+        if let Some(first) = placed.first() {
+          result.entry_point = first.object.entry_point.wrapping_add(bases[0]);
+        }
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+
+    for (input, base) in placed.iter().zip(bases.iter()) {
+      let base: u16 = *base;
+      let mut segments: Vec<Segment> = input.object.segments.clone();
+      for relocation in input.object.relocations.iter() {
+        if let Err(err) = Linker::patch(&mut segments, *relocation, base) {
+          errors.push(format!("Link Error: {} has a bad relocation, {}", input.name, err));
+        }
+      }
+      for import in input.object.imports.iter() {
+        let address: u16 = match exports.iter().find(|(export, _)| export.name == import.name) {
+          Some((export, _)) => export.address,
+          None => 0,
+        };
+        if let Err(err) = Linker::patch(&mut segments, import.address, address) {
+          errors.push(format!("Link Error: {} has a bad import, {}", input.name, err));
+        }
+      }
+      for mut segment in segments {
+        segment.load_address = segment.load_address.wrapping_add(base);
+        result.segments.push(segment);
+      }
+      for symbol in input.object.symbols.iter() {
+        result.symbols.push(Symbol {
+          name: symbol.name.clone(),
+          address: symbol.address.wrapping_add(base),
+        });
+      }
+      let mut files: Vec<u16> = Vec::new();
+      let names: Vec<String> = if input.object.files.is_empty() && !input.object.lines.is_empty() {
+        vec![String::new()]
+      } else {
+        input.object.files.clone()
+      };
+      for file in names.iter() {
+        match result.files.iter().position(|existing| existing == file) {
+          Some(index) => files.push(index as u16),
+          None => {
+            files.push(result.files.len() as u16);
+            result.files.push(file.clone());
+          }
+        }
+      }
+      for entry in input.object.lines.iter() {
+        result.lines.push(LineEntry {
+          address: entry.address.wrapping_add(base),
+          file: files.get(entry.file as usize).cloned().unwrap_or(0),
+          line: entry.line,
+        });
+      }
+      for relocation in input.object.relocations.iter() {
+        result.relocations.push(relocation.wrapping_add(base));
+      }
+      for import in input.object.imports.iter() {
+        result.relocations.push(import.address.wrapping_add(base));
+      }
+      for address in input.object.fixed.iter() {
+        result.fixed.push(address.wrapping_add(base));
+      }
+    }
+    result.exports = exports.into_iter().map(|(symbol, _)| symbol).collect();
+
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+    return Ok(result);
+  }
+}
+
+/// Lists the symbols imported by the placed objects which none of them export, along with the
+/// object importing each
+impl Linker {
+  fn missing_symbols(placed: &[&LinkInput]) -> Vec<(String, String)> {
+    let mut result: Vec<(String, String)> = Vec::new();
+    for input in placed.iter() {
+      for import in input.object.imports.iter() {
+        let exported: bool = placed
+          .iter()
+          .any(|other| other.object.exports.iter().any(|export| export.name == import.name));
+        if !exported && !result.iter().any(|(name, _)| *name == import.name) {
+          result.push((import.name.clone(), input.name.clone()));
+        }
+      }
+    }
+    return result;
+  }
+}
+
+/// Adds to the 16-bit little endian value at an address within an object's segments
+impl Linker {
+  fn patch(segments: &mut [Segment], address: u16, amount: u16) -> Result<(), String> {
+    for segment in segments.iter_mut() {
+      let start: usize = segment.load_address as usize;
+      let offset: usize = address as usize;
+      if offset >= start && offset + 2 <= start + segment.bytes.len() {
+        let position: usize = offset - start;
+        let value: u16 = (segment.bytes[position] as u16) | ((segment.bytes[position + 1] as u16) << 8);
+        let patched: u16 = value.wrapping_add(amount);
+        segment.bytes[position] = (patched & 0xff) as u8;
+        segment.bytes[position + 1] = (patched >> 8) as u8;
+        return Ok(());
+      }
+    }
+    return Err(format!("address 0x{:04x} is outside the object", address));
+  }
+}
+
+/// Parses a linker script, paths are made relative to the script
+pub fn parse_script(script_path: &Path, text: &str) -> Result<LinkerScript, String> {
+  let mut result: LinkerScript = LinkerScript {
+    placements: Vec::new(),
+    entry: None,
+  };
+  let directory: &Path = script_path.parent().unwrap_or(Path::new(""));
+  for (index, line) in text.lines().enumerate() {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() || words[0].starts_with('#') {
+      continue;
+    }
+    if words[0] == "ENTRY" {
+      if words.len() != 2 {
+        return Err(format!("Linker Script Error: line {} should be ENTRY NAME", index + 1));
+      }
+      result.entry = Some(words[1].strip_prefix(':').unwrap_or(words[1]).to_string());
+      continue;
+    }
+    if words.len() > 2 {
+      return Err(format!(
+        "Linker Script Error: line {} should be a path optionally followed by an address",
+        index + 1
+      ));
+    }
+    let mut address: Option<u16> = None;
+    if words.len() == 2 {
+      match parse_address(words[1]) {
+        Some(parsed) => address = Some(parsed),
+        None => {
+          return Err(format!(
+            "Linker Script Error: invalid address \"{}\" on line {}",
+            words[1],
+            index + 1
+          ))
+        }
+      }
+    }
+    result.placements.push((directory.join(words[0]).display().to_string(), address));
+  }
+  return Ok(result);
+}
+
+/// Parses an address written in hex (0x...) or decimal
+pub fn parse_address(to_parse: &str) -> Option<u16> {
+  if let Some(digits) = to_parse.strip_prefix("0x") {
+    return u16::from_str_radix(digits, 16).ok();
+  }
+  return to_parse.parse::<u16>().ok();
+}
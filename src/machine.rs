@@ -1,7 +1,12 @@
 //! Representation of the Rusty Jello machine
 
 use std::fmt;
+use bus::{Bus, Device};
 use instructions;
+use interrupts::{InterruptController, INTERRUPT_LINES, SERIAL_INPUT_LINE};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 use std::time::Duration;
 use std::thread;
 
@@ -17,28 +22,61 @@ pub struct Flags {
   pub carry: bool,
   pub overflow: bool,
   pub test: bool,
+  pub interrupt: bool,
 }
 
 impl fmt::Debug for Flags {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(
       f,
-      "{{halt: {}, carry: {}, overflow: {}, test: {}}}",
+      "{{halt: {}, carry: {}, overflow: {}, test: {}, interrupt: {}}}",
       self.halt,
       self.carry,
       self.overflow,
-      self.test
+      self.test,
+      self.interrupt
     )
   }
 }
 
+/// Packs the flags into a 16-bit value, with each flag at its bit from the architecture
+impl Flags {
+  pub fn to_word(&self) -> u16 {
+    return (self.carry as u16)
+      | (self.overflow as u16) << 1
+      | (self.test as u16) << 2
+      | (self.interrupt as u16) << 3
+      | (self.halt as u16) << 15;
+  }
+}
+
+/// Sets the flags from a value packed by to_word, apart from halt which is left as it is
+impl Flags {
+  pub fn restore_word(&mut self, word: u16) {
+    self.carry = word & 1 != 0;
+    self.overflow = word & (1 << 1) != 0;
+    self.test = word & (1 << 2) != 0;
+    self.interrupt = word & (1 << 3) != 0;
+  }
+}
+
 pub struct SerialBuffer {
   buffer: Vec<u8>,
+  closed: bool,
+}
+
+impl Default for SerialBuffer {
+  fn default() -> SerialBuffer {
+    return SerialBuffer::new();
+  }
 }
 
 impl SerialBuffer {
   pub fn new() -> SerialBuffer {
-    return SerialBuffer { buffer: Vec::new() };
+    return SerialBuffer {
+      buffer: Vec::new(),
+      closed: false,
+    };
   }
 }
 
@@ -104,11 +142,17 @@ impl SerialBuffer {
   }
 }
 
+impl SerialBuffer {
+  pub fn peek(&self) -> Option<u8> {
+    self.buffer.last().cloned()
+  }
+}
+
 impl SerialBuffer {
   pub fn take_all(&mut self) -> Vec<u8> {
     let mut result: Vec<u8> = Vec::new();
-    while self.buffer.len() > 0 {
-      result.push(self.buffer.pop().unwrap());
+    while let Some(byte) = self.buffer.pop() {
+      result.push(byte);
     }
     return result;
   }
@@ -122,13 +166,52 @@ impl SerialBuffer {
 
 impl SerialBuffer {
   pub fn has_bytes(&self) -> bool {
-    self.buffer.len() > 0
+    !self.buffer.is_empty()
+  }
+}
+
+impl SerialBuffer {
+  pub fn len(&self) -> usize {
+    self.buffer.len()
+  }
+}
+
+impl SerialBuffer {
+  pub fn is_empty(&self) -> bool {
+    self.buffer.is_empty()
+  }
+}
+
+/// Number of bytes that can be put before the buffer is full
+impl SerialBuffer {
+  pub fn free_space(&self) -> usize {
+    256 - self.buffer.len()
+  }
+}
+
+/// Marks the buffer as having no more bytes coming, i.e. end of input
+impl SerialBuffer {
+  pub fn close(&mut self) {
+    self.closed = true;
+  }
+}
+
+impl SerialBuffer {
+  pub fn is_closed(&self) -> bool {
+    self.closed
   }
 }
 
 pub struct Stack {
   stack_pointer: u8,
   stack: [u16; 16],
+  fault: Option<FaultKind>,
+}
+
+impl Default for Stack {
+  fn default() -> Stack {
+    return Stack::new();
+  }
 }
 
 impl Stack {
@@ -136,13 +219,16 @@ impl Stack {
     return Stack {
       stack_pointer: 0,
       stack: [0; 16],
+      fault: None,
     };
   }
 }
 
+/// Pushing onto a full stack shifts the oldest item out and popping an empty stack gives 0, both
+/// are remembered as a fault for the machine to raise once the instruction has finished
 impl Stack {
   pub fn push(&mut self, item: u16) -> bool {
-    if self.stack_pointer < 15 {
+    if (self.stack_pointer as usize) < self.stack.len() {
       self.stack[self.stack_pointer as usize] = item;
       self.stack_pointer += 1;
       return true;
@@ -150,7 +236,8 @@ impl Stack {
     for i in 0..15 {
       self.stack[i] = self.stack[i + 1];
     }
-    self.stack[self.stack_pointer as usize] = item;
+    self.stack[15] = item;
+    self.fault = Some(FaultKind::StackOverflow);
     return false;
   }
   pub fn pop(&mut self) -> u16 {
@@ -158,10 +245,25 @@ impl Stack {
       self.stack_pointer -= 1;
       return self.stack[self.stack_pointer as usize];
     }
+    self.fault = Some(FaultKind::StackUnderflow);
     return 0;
   }
 }
 
+/// Takes the fault from the last push or pop which overflowed or underflowed the stack
+impl Stack {
+  pub fn take_fault(&mut self) -> Option<FaultKind> {
+    return self.fault.take();
+  }
+}
+
+/// Number of items currently on the stack
+impl Stack {
+  pub fn depth(&self) -> usize {
+    self.stack_pointer as usize
+  }
+}
+
 impl fmt::Debug for Stack {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     let mut stack: String = String::new();
@@ -175,6 +277,121 @@ impl fmt::Debug for Stack {
   }
 }
 
+/// Conditions which stop the machine from carrying on normally, each has a status code which is
+/// put in R3 when the fault is trapped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultKind {
+  IllegalInstruction,
+  StackOverflow,
+  StackUnderflow,
+  DivideByZero,
+  UnknownSyscall,
+}
+
+pub const FAULT_KINDS: [FaultKind; 5] = [
+  FaultKind::IllegalInstruction,
+  FaultKind::StackOverflow,
+  FaultKind::StackUnderflow,
+  FaultKind::DivideByZero,
+  FaultKind::UnknownSyscall,
+];
+
+impl FaultKind {
+  pub fn code(&self) -> u16 {
+    match *self {
+      FaultKind::IllegalInstruction => 1,
+      FaultKind::StackOverflow => 2,
+      FaultKind::StackUnderflow => 3,
+      FaultKind::DivideByZero => 4,
+      FaultKind::UnknownSyscall => 5,
+    }
+  }
+}
+
+/// Name of the fault as used on the command line, such as stack-overflow
+impl FaultKind {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      FaultKind::IllegalInstruction => "illegal-instruction",
+      FaultKind::StackOverflow => "stack-overflow",
+      FaultKind::StackUnderflow => "stack-underflow",
+      FaultKind::DivideByZero => "divide-by-zero",
+      FaultKind::UnknownSyscall => "unknown-syscall",
+    }
+  }
+}
+
+/// Position of the fault in FAULT_KINDS, which is also where the machine keeps its policy
+impl FaultKind {
+  pub fn index(&self) -> usize {
+    return FAULT_KINDS.iter().position(|kind| kind == self).unwrap();
+  }
+}
+
+impl FaultKind {
+  pub fn from_name(name: &str) -> Option<FaultKind> {
+    return FAULT_KINDS.iter().find(|kind| kind.name() == name).cloned();
+  }
+}
+
+impl fmt::Display for FaultKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      FaultKind::IllegalInstruction => write!(f, "illegal instruction"),
+      FaultKind::StackOverflow => write!(f, "stack overflow"),
+      FaultKind::StackUnderflow => write!(f, "stack underflow"),
+      FaultKind::DivideByZero => write!(f, "divide by zero"),
+      FaultKind::UnknownSyscall => write!(f, "unknown syscall"),
+    }
+  }
+}
+
+/// A fault along with the address and opcode of the instruction which caused it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fault {
+  pub kind: FaultKind,
+  pub address: u16,
+  pub op_code: u8,
+}
+
+impl fmt::Display for Fault {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} (opcode 0x{:02x}) at 0x{:04x}", self.kind, self.op_code, self.address)
+  }
+}
+
+/// What the machine does when a fault is raised. Ignoring it keeps the behaviour from before faults
+/// existed, where a full stack loses its oldest item, an empty stack pops as 0, dividing by zero
+/// gives 0 and an unknown opcode is stuck on forever
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultPolicy {
+  Ignore,
+  Halt,
+  Trap(u16),
+}
+
+/// Why a step (or a run of steps) stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StepResult {
+  /// An instruction ran and the machine can carry on, or a run used up its cycles
+  Executed,
+  /// The machine has halted, either now or on an earlier step
+  Halted,
+  /// The machine has halted on a fault
+  Faulted(Fault),
+  /// The instruction needs input which isn't in the input buffer yet, it runs again on the next
+  /// step
+  WaitingForInput,
+  /// An instruction ran and the instruction pointer is now on a breakpoint
+  Breakpoint(u16),
+  /// An instruction ran and the condition a run was waiting for now holds
+  Stopped,
+}
+
+/// A host function which Jello code calls with SYSCALL, it takes its arguments from and leaves its
+/// results in the machine's stack and registers
+pub type Syscall = Rc<RefCell<dyn FnMut(&mut Machine)>>;
+
 pub struct Machine {
   pub memory: [u8; 65536],
   pub registers: [u16; 4],
@@ -186,6 +403,21 @@ pub struct Machine {
   pub clock_speed_hz: f64,
   pub output_buffer: SerialBuffer,
   pub input_buffer: SerialBuffer,
+  pub waiting_for_input: bool,
+  pub fault_policies: [FaultPolicy; FAULT_KINDS.len()],
+  pub fault: Option<Fault>,
+  pub breakpoints: Vec<u16>,
+  pub cycles: u64,
+  pub interrupts: InterruptController,
+  trap_depth: Option<usize>,
+  syscalls: HashMap<u8, Syscall>,
+  bus: Bus,
+}
+
+impl Default for Machine {
+  fn default() -> Machine {
+    return Machine::new();
+  }
 }
 
 impl Machine {
@@ -202,10 +434,28 @@ impl Machine {
         carry: false,
         overflow: false,
         test: false,
+        interrupt: false,
       },
       clock_speed_hz: 0.0,
       output_buffer: SerialBuffer::new(),
       input_buffer: SerialBuffer::new(),
+      waiting_for_input: false,
+      // Programs such as print-test.jello rely on an empty stack popping as 0, so stack faults are
+      // ignored unless asked for
+      fault_policies: [
+        FaultPolicy::Halt,
+        FaultPolicy::Ignore,
+        FaultPolicy::Ignore,
+        FaultPolicy::Halt,
+        FaultPolicy::Halt,
+      ],
+      fault: None,
+      breakpoints: Vec::new(),
+      cycles: 0,
+      interrupts: InterruptController::new(),
+      trap_depth: None,
+      syscalls: HashMap::new(),
+      bus: Bus::new(),
     };
   }
 }
@@ -241,17 +491,273 @@ impl Machine {
   }
 }
 
+/// Runs the instruction at the instruction pointer, unless the machine has already halted
 impl Machine {
-  pub fn step(&mut self) {
-    let loc: u8 = self.memory[self.instruction_pointer as usize];
-    if let Some(inst) = instructions::find_inst_by_opcode(&loc) {
-      (inst.run)(self);
-      if self.clock_speed_hz != 0.0 {
-        let instruction_speed: f64 =
-          (inst.clock_cycles as f64) * (1.0f64 / self.clock_speed_hz) * 1000.0f64;
-        thread::sleep(Duration::from_millis(instruction_speed as u64));
+  pub fn step(&mut self) -> StepResult {
+    if self.flags.halt {
+      return self.halted_result();
+    }
+    let address: u16 = self.instruction_pointer;
+    let loc: u8 = self.memory[address as usize];
+    match instructions::find_inst_by_opcode(&loc) {
+      Some(inst) => {
+        self.cycles += inst.clock_cycles as u64;
+        self.stack.take_fault();
+        self.instruction_pointer_stack.take_fault();
+        (inst.run)(self);
+        self.bus.tick(inst.clock_cycles as u64, &mut self.interrupts);
+        if self.trap_depth.is_some_and(|depth| self.instruction_pointer_stack.depth() < depth) {
+          self.trap_depth = None;
+        }
+        if let Some(kind) = self.stack.take_fault().or(self.instruction_pointer_stack.take_fault()) {
+          self.raise_fault_after(kind, address);
+        }
+        if self.clock_speed_hz != 0.0 {
+          let instruction_speed: f64 =
+            (inst.clock_cycles as f64) * (1.0f64 / self.clock_speed_hz) * 1000.0f64;
+          thread::sleep(Duration::from_millis(instruction_speed as u64));
+        }
+      }
+      None => {
+        self.cycles += 1;
+        self.raise_fault(FaultKind::IllegalInstruction);
+        self.bus.tick(1, &mut self.interrupts);
+      }
+    }
+    if self.flags.interrupt && !self.flags.halt {
+      self.service_interrupt(address);
+    }
+
+    if self.flags.halt {
+      return self.halted_result();
+    }
+    if self.waiting_for_input {
+      return StepResult::WaitingForInput;
+    }
+    if self.breakpoints.contains(&self.instruction_pointer) {
+      return StepResult::Breakpoint(self.instruction_pointer);
+    }
+    return StepResult::Executed;
+  }
+}
+
+impl Machine {
+  fn halted_result(&self) -> StepResult {
+    match self.fault {
+      Some(fault) => StepResult::Faulted(fault),
+      None => StepResult::Halted,
+    }
+  }
+}
+
+/// Steps the machine until it has used up a budget of clock cycles, or stops for any other reason,
+/// giving Executed if the budget ran out
+impl Machine {
+  pub fn run_for(&mut self, cycles: u64) -> StepResult {
+    return self.run_until(cycles, |_: &Machine| false);
+  }
+}
+
+/// Steps the machine until a condition checked after each step holds, it has used up a budget of
+/// clock cycles, or stops for any other reason. Gives Stopped if the condition held and Executed
+/// if the budget ran out
+impl Machine {
+  pub fn run_until<F: FnMut(&Machine) -> bool>(&mut self, cycles: u64, mut stop: F) -> StepResult {
+    let end: u64 = self.cycles.saturating_add(cycles);
+    while self.cycles < end {
+      let result: StepResult = self.step();
+      if result != StepResult::Executed {
+        return result;
+      }
+      if stop(self) {
+        return StepResult::Stopped;
+      }
+    }
+    return StepResult::Executed;
+  }
+}
+
+/// Calls the handler for the lowest raised line with one, as if the instruction which just ran were followed
+/// by a CALL to it. The flags are pushed onto the instruction pointer stack after the return address
+/// and interrupts are disabled, until RETI puts both back
+impl Machine {
+  fn service_interrupt(&mut self, address: u16) {
+    let mut lines: u8 = self.interrupts.pending();
+    if self.input_buffer.has_bytes() {
+      lines |= 1 << SERIAL_INPUT_LINE;
+    }
+    if lines == 0 {
+      return;
+    }
+    // A line whose vector is 0 has no handler, so it is passed over and a latched line stays raised
+    let mut found: Option<(u8, u16)> = None;
+    for line in 0..INTERRUPT_LINES {
+      if lines & (1 << line) != 0 {
+        let vector: u16 = self.interrupts.vector_address(line);
+        let handler: u16 = self.read_word(vector);
+        if handler != 0 {
+          found = Some((line, handler));
+          break;
+        }
+      }
+    }
+    let (line, handler) = match found {
+      Some(found) => found,
+      None => return,
+    };
+    self.interrupts.clear(line);
+    self.instruction_pointer_stack.push(self.instruction_pointer.wrapping_sub(1));
+    self.instruction_pointer_stack.push(self.flags.to_word());
+    self.flags.interrupt = false;
+    // An instruction waiting for input runs again once the handler returns, the handler itself isn't
+    // waiting
+    self.waiting_for_input = false;
+    self.instruction_pointer = handler;
+    if let Some(kind) = self.instruction_pointer_stack.take_fault() {
+      self.raise_fault_after(kind, address);
+    }
+  }
+}
+
+/// Raises an interrupt line from the host, returning false if there is no such line. The serial
+/// input line doesn't need raising, it is raised whenever the input buffer has bytes in it
+impl Machine {
+  pub fn raise_interrupt(&mut self, line: u8) -> bool {
+    return self.interrupts.raise(line);
+  }
+}
+
+/// Maps a device into memory from start to start + length, so loads and stores there go to the
+/// device instead of RAM. Instructions are always fetched from RAM
+impl Machine {
+  pub fn map_device<D: Device + 'static>(&mut self, start: u16, length: usize, device: D) -> Result<(), String> {
+    return self.bus.map(start, length, Box::new(device));
+  }
+}
+
+/// Reads a byte through the bus, from a device if one is mapped at the address or RAM otherwise
+impl Machine {
+  pub fn read_byte(&mut self, address: u16) -> u8 {
+    return self.bus.read(&self.memory, address);
+  }
+}
+
+/// Writes a byte through the bus, to a device if one is mapped at the address or RAM otherwise
+impl Machine {
+  pub fn write_byte(&mut self, address: u16, value: u8) {
+    self.bus.write(&mut self.memory, address, value);
+  }
+}
+
+/// Reads a little endian 16-bit value through the bus, wrapping round the end of memory
+impl Machine {
+  pub fn read_word(&mut self, address: u16) -> u16 {
+    let part1: u16 = self.read_byte(address) as u16;
+    let part2: u16 = (self.read_byte(address.wrapping_add(1)) as u16) << 8;
+    return part1 | part2;
+  }
+}
+
+/// Writes a little endian 16-bit value through the bus, wrapping round the end of memory
+impl Machine {
+  pub fn write_word(&mut self, address: u16, value: u16) {
+    self.write_byte(address, (value & 0xff) as u8);
+    self.write_byte(address.wrapping_add(1), ((value >> 8) & 0xff) as u8);
+  }
+}
+
+/// Registers the handler SYSCALL calls for a number, replacing any handler already registered for
+/// it
+impl Machine {
+  pub fn register_syscall<F: FnMut(&mut Machine) + 'static>(&mut self, number: u8, handler: F) {
+    self.syscalls.insert(number, Rc::new(RefCell::new(handler)));
+  }
+}
+
+/// Removes the handler for a number, returning false if there wasn't one
+impl Machine {
+  pub fn remove_syscall(&mut self, number: u8) -> bool {
+    return self.syscalls.remove(&number).is_some();
+  }
+}
+
+/// Calls the handler registered for a number, returning false if there isn't one. A handler can't
+/// call itself, so one which steps the machine into the same SYSCALL finds no handler there
+impl Machine {
+  pub fn call_syscall(&mut self, number: u8) -> bool {
+    let handler: Syscall = match self.syscalls.get(&number) {
+      Some(handler) => handler.clone(),
+      None => return false,
+    };
+    match handler.try_borrow_mut() {
+      Ok(mut handler) => (*handler)(self),
+      Err(_) => return false,
+    }
+    return true;
+  }
+}
+
+impl Machine {
+  pub fn fault_policy(&self, kind: FaultKind) -> FaultPolicy {
+    return self.fault_policies[kind.index()];
+  }
+}
+
+impl Machine {
+  pub fn set_fault_policy(&mut self, kind: FaultKind, policy: FaultPolicy) {
+    self.fault_policies[kind.index()] = policy;
+    return;
+  }
+}
+
+/// Raises a fault for the instruction at the instruction pointer while it is running, returning
+/// true if the fault is ignored and the instruction should carry on. Halting records the fault,
+/// while trapping calls the handler as if the faulting instruction were a CALL, with the fault's
+/// status code in R3, so returning from the handler carries on after the faulting opcode
+impl Machine {
+  pub fn raise_fault(&mut self, kind: FaultKind) -> bool {
+    let address: u16 = self.instruction_pointer;
+    return self.handle_fault(kind, address, address);
+  }
+}
+
+/// Raises a fault for an instruction which has already finished, trapping returns to wherever the
+/// instruction would have carried on from
+impl Machine {
+  fn raise_fault_after(&mut self, kind: FaultKind, address: u16) {
+    let return_address: u16 = self.instruction_pointer.wrapping_sub(1);
+    self.handle_fault(kind, address, return_address);
+  }
+}
+
+impl Machine {
+  fn handle_fault(&mut self, kind: FaultKind, address: u16, return_address: u16) -> bool {
+    let mut policy: FaultPolicy = self.fault_policy(kind);
+    if self.trap_depth.is_some() && matches!(policy, FaultPolicy::Trap(..)) {
+      // A fault in a trap handler would most likely trap again forever, so it halts instead
+      policy = FaultPolicy::Halt;
+    }
+    match policy {
+      FaultPolicy::Ignore => return true,
+      FaultPolicy::Halt => {
+        self.fault = Some(Fault {
+          kind,
+          address,
+          op_code: self.memory[address as usize],
+        });
+        self.flags.halt = true;
+      }
+      FaultPolicy::Trap(handler) => {
+        self.instruction_pointer_stack.push(return_address);
+        self.trap_depth = Some(self.instruction_pointer_stack.depth());
+        self.registers[Register::R3 as usize] = kind.code();
+        self.instruction_pointer = handler;
       }
     }
+    // Only the first fault of an instruction is raised, which includes any from calling the handler
+    self.stack.take_fault();
+    self.instruction_pointer_stack.take_fault();
+    return false;
   }
 }
 
@@ -260,11 +766,10 @@ impl Machine {
     let loc: u8 = self.memory[self.instruction_pointer as usize];
     if let Some(inst) = instructions::find_inst_by_opcode(&loc) {
       let mut data: String = String::new();
-      let mut byte_count: u16 = 0;
-      for _ in 0..((inst.bytes_per_arg as usize) * (inst.num_args as usize)) {
+      for byte_count in 0..(inst.bytes_per_arg as u16 * inst.num_args as u16) {
         if byte_count != 0 {
           data += " ";
-          if byte_count % inst.bytes_per_arg as u16 == 0 {
+          if byte_count.is_multiple_of(inst.bytes_per_arg as u16) {
             data += ". ";
           }
         }
@@ -272,7 +777,6 @@ impl Machine {
           "{:02x}",
           self.memory[(self.instruction_pointer + 1 + byte_count) as usize]
         ).as_str();
-        byte_count += 1;
       }
       return format!(
         "[{:04x}] {} ({:02x} | {})",
@@ -292,16 +796,16 @@ impl fmt::Debug for Machine {
     write!(
       f,
       "Machine {{
-          acc: {},
-          ip: {},
+          acc: {:04x}: ,
+          ip: {:04x}: ,
           ip_stack: {:?},
           stack: {:?},
           flags: {:?},
           sp_reg: {{r0:{:04x}, r1:{:04x}, r2:{:04x}, r3:{:04x}}},
           memory: {}
         }}",
-      format!("{:04x}: ", self.accumulator),
-      format!("{:04x}: ", self.instruction_pointer),
+      self.accumulator,
+      self.instruction_pointer,
       self.instruction_pointer_stack,
       self.stack,
       self.flags,
@@ -1,13 +1,18 @@
-//! Rusty Jello is a simple (and a bit hack-y) language created by Jacob Allen
+//! Command line front end for Rusty Jello, assembling, linking, disassembling, running and
+//! debugging programs with the rusty_jello library
+
+extern crate rusty_jello;
 
 pub mod arguments;
-pub mod machine;
-pub mod assembler;
-pub mod instructions;
+pub mod debugger;
 
 use arguments::Args;
-use machine::Machine;
-use assembler::Assembler;
+use debugger::Debugger;
+use rusty_jello::interrupts::TIMER_LINE;
+use rusty_jello::linker;
+use rusty_jello::machine::{FaultKind, FaultPolicy, FAULT_KINDS};
+use rusty_jello::timer::TIMER_LENGTH;
+use rusty_jello::{Assembler, Disassembler, InputPump, Linker, Machine, ObjectFile, SourceMap, StepResult, Timer};
 
 use std::io;
 use std::env;
@@ -15,6 +20,7 @@ use std::fs::File;
 use std::path::Path;
 use std::io::prelude::*;
 use std::io::ErrorKind;
+use std::process;
 use std::time::Instant;
 use std::time::Duration;
 
@@ -27,10 +33,16 @@ fn main() {
       "-a".to_string(),
       "-m".to_string(),
       "-q".to_string(),
+      "-d".to_string(),
+      "-r".to_string(),
+      "-raw".to_string(),
+      "-dbg".to_string(),
+      "-link".to_string(),
     ],
   );
   let mut input_file: String = "".to_string();
   let mut output_file: String = "a.ja".to_string();
+  let mut program_input_file: String = "".to_string();
   let debug_level: u8;
   let tick_rate: f64;
 
@@ -40,7 +52,7 @@ fn main() {
     println!("Rusty Jello v0.7.1 (Rumbustious) by Jacob Allen");
   }
 
-  if args.has_arg("") {
+  if args.has_arg("") && !args.has_arg("-link") {
     if args.count_arg("") > 1 {
       println!("Rusty Jello only needs 1 input file name")
     } else {
@@ -57,13 +69,29 @@ fn main() {
     println!("  -v: Displays version");
     println!("  -h: Displays this help section");
     println!("  -a: Assemble only");
+    println!("  -raw: Assemble to a headerless memory dump instead of an object file");
+    println!("  -r: Run bytecode file (default for .ja files and object files)");
+    println!("  -d: Disassemble bytecode file (to output file if -o is given)");
     println!("  -m: Measure time");
     println!("  -q: Show only program output");
     println!("  -b: Buffer output");
+    println!("  -dbg: Run in the interactive debugger (program input only comes from -i)");
+    println!("  -link: Link the input object files (any number of them) into one, written to -o");
     println!("Options:");
     println!("  -dbl: Sets the debug level, can be 0 to 2  (default: 0)");
     println!("  -t: Sets internal clock rate in hertz (default: 0)");
     println!("  -o: Output file path (default: a.ja)");
+    println!("  -lst: Listing file path, written when assembling");
+    println!("  -i: Program input file path (default: stdin)");
+    println!("  -I: Directory to search for included files, can be given more than once");
+    println!("  -lib: Object file to link only if it is needed, can be given more than once");
+    println!("  -ls: Linker script placing objects at addresses");
+    println!("  -org: Address to link the first object at (default: 0x0000)");
+    println!("  -trap: Address or :LABEL of a handler to call on a machine fault, instead of halting");
+    println!("  -fault: Sets what a fault does as NAME=ignore, NAME=halt or NAME=ADDRESS to trap to a");
+    println!("          handler, can be given more than once (faults: illegal-instruction, stack-overflow,");
+    println!("          stack-underflow, divide-by-zero, unknown-syscall)");
+    println!("  -timer: Address or :LABEL to map a timer at, which raises interrupt line 1");
     return;
   }
 
@@ -73,6 +101,12 @@ fn main() {
 
   let assemble_mode: bool = args.has_arg("-a");
 
+  let disassemble_mode: bool = args.has_arg("-d");
+
+  let raw_mode: bool = args.has_arg("-raw");
+
+  let debugger_mode: bool = args.has_arg("-dbg");
+
   let buffer_mode: bool = args.has_arg("-b");
 
   let measure_time: bool = args.has_arg("-m");
@@ -105,14 +139,20 @@ fn main() {
     None => tick_rate = 0.0,
   }
 
-  match args.get_arg("-o") {
-    Some(arg) => {
-      output_file = arg.value.to_string();
-    }
-    None => {}
+  if let Some(arg) = args.get_arg("-o") {
+    output_file = arg.value.to_string();
   }
 
-  if input_file == "" {
+  if let Some(arg) = args.get_arg("-i") {
+    program_input_file = arg.value.to_string();
+  }
+
+  if args.has_arg("-link") {
+    link_objects(&args, &output_file, raw_mode, quiet_mode);
+    return;
+  }
+
+  if input_file.is_empty() {
     println!("Rusty Jello requires an input file to run");
     return;
   }
@@ -123,7 +163,7 @@ fn main() {
 
   let mut file: File;
   let input_file_path: &Path = Path::new(&input_file);
-  let mut code: String = String::new();
+  let mut file_bytes: Vec<u8> = Vec::new();
 
   match File::open(input_file_path) {
     Ok(_file) => file = _file,
@@ -137,7 +177,7 @@ fn main() {
     }
   }
 
-  match file.read_to_string(&mut code) {
+  match file.read_to_end(&mut file_bytes) {
     Ok(..) => if !quiet_mode {
       println!("Done.");
     },
@@ -147,37 +187,144 @@ fn main() {
     }
   }
 
-  let mut assembler: Assembler = Assembler::new();
-  let bytecode: Vec<u8>;
+  let bytecode_mode: bool = disassemble_mode
+    || args.has_arg("-r")
+    || input_file_path.extension().is_some_and(|extension| extension == "ja")
+    || ObjectFile::is_object(&file_bytes);
 
-  if !quiet_mode {
-    print!("Assembling file... ");
-  }
+  let object: ObjectFile;
+
+  if bytecode_mode {
+    if assemble_mode {
+      println!("Input file is already bytecode, there is nothing to assemble");
+      return;
+    }
+    if ObjectFile::is_object(&file_bytes) {
+      match ObjectFile::from_bytes(&file_bytes) {
+        Ok(loaded_object) => object = loaded_object,
+        Err(err) => {
+          println!("{}", err);
+          return;
+        }
+      }
+    } else {
+      if file_bytes.len() > 65536 {
+        println!("Bytecode file is too large to fit in memory");
+        return;
+      }
+      object = ObjectFile::from_raw(file_bytes);
+    }
+  } else {
+    let code: String = match String::from_utf8(file_bytes) {
+      Ok(text) => text,
+      Err(..) => {
+        println!("Input file is not valid text, use -r to run or -d to disassemble bytecode");
+        return;
+      }
+    };
 
-  assembler.add_string(code.as_str());
+    let mut assembler: Assembler = Assembler::new();
 
-  let assembly_start_time = Instant::now();
-  match assembler.assemble() {
-    Ok(generated_bytecode) => {
-      bytecode = generated_bytecode;
+    if !quiet_mode {
+      print!("Assembling file... ");
     }
-    Err(err) => {
-      println!("Failed!");
-      println!("{}", err);
-      return;
+
+    assembler.add_file_string(input_file_path, code.as_str());
+    for arg in args.get_args("-I") {
+      assembler.add_search_path(Path::new(&arg.value));
+    }
+
+    let assembly_start_time = Instant::now();
+    match assembler.assemble_object() {
+      Ok(generated_object) => {
+        object = generated_object;
+      }
+      Err(diagnostics) => {
+        println!("Failed!");
+        let mut error_count: usize = 0;
+        for diagnostic in diagnostics.iter() {
+          println!("{}", diagnostic);
+          println!();
+          if diagnostic.is_error() {
+            error_count += 1;
+          }
+        }
+        println!(
+          "{} error(s), {} warning(s)",
+          error_count,
+          diagnostics.len() - error_count
+        );
+        return;
+      }
     }
-  }
 
-  let assembly_duration: Duration = assembly_start_time.elapsed();
-  let assembly_elapsed: f64 =
-    assembly_duration.as_secs() as f64 + assembly_duration.subsec_nanos() as f64 * 1e-9;
+    let assembly_duration: Duration = assembly_start_time.elapsed();
+    let assembly_elapsed: f64 =
+      assembly_duration.as_secs() as f64 + assembly_duration.subsec_nanos() as f64 * 1e-9;
 
-  if !quiet_mode {
-    println!("Done.");
+    if !quiet_mode {
+      println!("Done.");
+    }
+
+    for diagnostic in assembler.diagnostics().iter() {
+      println!("{}", diagnostic);
+      println!();
+    }
+
+    if let Some(arg) = args.get_arg("-lst") {
+      write_listing(assembler.listing(), &arg.value, quiet_mode);
+    }
+
+    if measure_time {
+      println!("Assembly took {:.8} seconds", assembly_elapsed);
+    }
   }
 
-  if measure_time {
-    println!("Assembly took {:.8} seconds", assembly_elapsed);
+  if disassemble_mode {
+    if !quiet_mode {
+      print!("Disassembling file... ");
+    }
+
+    let mut disassembler: Disassembler = Disassembler::new();
+    disassembler.add_bytes(&object.to_raw());
+    let source: String = disassembler.disassemble();
+
+    if !quiet_mode {
+      println!("Done.");
+    }
+
+    if args.has_arg("-o") {
+      let mut out_file: File;
+      let output_file_path: &Path = Path::new(&output_file);
+
+      match File::create(output_file_path) {
+        Ok(_file) => out_file = _file,
+        Err(err) => {
+          match err.kind() {
+            ErrorKind::PermissionDenied => println!("Output file access denied"),
+            _ => println!("Error opening file, {:?}", err),
+          }
+          return;
+        }
+      }
+
+      if !quiet_mode {
+        print!("Writing file... ");
+      }
+
+      match out_file.write_all(source.as_bytes()) {
+        Ok(..) => if !quiet_mode {
+          println!("Done.");
+        },
+        Err(err) => {
+          println!("Error writing file, {:?}", err);
+          return;
+        }
+      }
+    } else {
+      print!("{}", source);
+    }
+    return;
   }
 
   if !assemble_mode {
@@ -188,16 +335,85 @@ fn main() {
     let mut machine: Machine = Machine::new();
     machine.clock_speed_hz = tick_rate;
 
-    let mut pointer: usize = 0;
-    for byte in bytecode {
-      machine.memory[pointer] = byte;
-      pointer += 1;
+    let mut input_pump: InputPump;
+    if program_input_file.is_empty() {
+      if debugger_mode {
+        input_pump = InputPump::new(io::empty());
+      } else {
+        input_pump = InputPump::new(io::stdin());
+      }
+    } else {
+      match File::open(Path::new(&program_input_file)) {
+        Ok(_file) => input_pump = InputPump::new(_file),
+        Err(err) => {
+          match err.kind() {
+            ErrorKind::NotFound => println!("Program input file '{}' does not exist", program_input_file),
+            ErrorKind::PermissionDenied => println!("Program input file access denied"),
+            _ => println!("Error opening file, {:?}", err),
+          }
+          return;
+        }
+      }
+    }
+
+    match object.load_into(&mut machine) {
+      Ok(..) => {}
+      Err(err) => {
+        println!("Failed!");
+        println!("{}", err);
+        return;
+      }
     }
 
     if !quiet_mode {
       println!("Done.");
     }
 
+    let source_map: SourceMap = SourceMap::new(&object);
+
+    if let Some(arg) = args.get_arg("-trap") {
+      match parse_location(&source_map, &arg.value) {
+        Some(address) => {
+          for kind in FAULT_KINDS.iter() {
+            machine.set_fault_policy(*kind, FaultPolicy::Trap(address));
+          }
+        }
+        None => {
+          println!("Invalid trap handler \"{}\"", arg.value);
+          return;
+        }
+      }
+    }
+    for arg in args.get_args("-fault") {
+      match parse_fault_policy(&source_map, &arg.value) {
+        Ok((kind, policy)) => machine.set_fault_policy(kind, policy),
+        Err(err) => {
+          println!("{}", err);
+          return;
+        }
+      }
+    }
+    if let Some(arg) = args.get_arg("-timer") {
+      match parse_location(&source_map, &arg.value) {
+        Some(address) => {
+          if let Err(err) = machine.map_device(address, TIMER_LENGTH, Timer::new(TIMER_LINE)) {
+            println!("{}", err);
+            return;
+          }
+        }
+        None => {
+          println!("Invalid timer address \"{}\"", arg.value);
+          return;
+        }
+      }
+    }
+
+    if debugger_mode {
+      let mut debugger: Debugger = Debugger::new(source_map);
+      debugger.run(&mut machine, &mut input_pump);
+      return;
+    }
+
     if !quiet_mode {
       println!("Running virtual machine until halt... ");
     }
@@ -215,14 +431,16 @@ fn main() {
 
     let mut stdout = io::stdout();
 
-    while !machine.flags.halt {
+    let mut result: StepResult = StepResult::Executed;
+    while !matches!(result, StepResult::Halted | StepResult::Faulted(..)) {
       if debug_level > 1 {
         println!("{:?}", machine);
       }
       if debug_level > 0 {
-        println!("{}", machine.format_inst());
+        println!("{} {}", source_map.describe(machine.instruction_pointer), machine.format_inst());
       }
-      machine.step();
+      result = machine.step();
+      input_pump.pump(&mut machine);
       if machine.output_buffer.has_bytes(){
         for byte in machine.output_buffer.take_all() {
           if buffer_mode {
@@ -230,7 +448,7 @@ fn main() {
           }else{
             write!(stdout, "{}", byte as char).unwrap();
             if debug_level > 0 {
-              write!(stdout, "\n").unwrap();
+              writeln!(stdout).unwrap();
             }
             stdout.flush().unwrap();
           }
@@ -238,6 +456,16 @@ fn main() {
       }
     }
 
+    if let StepResult::Faulted(fault) = result {
+      println!();
+      println!(
+        "Machine fault, {} (opcode 0x{:02x}) at {}",
+        fault.kind,
+        fault.op_code,
+        source_map.describe(fault.address)
+      );
+    }
+
     let execution_duration: Duration = execution_start_time.elapsed();
     let execution_elapsed: f64 =
       execution_duration.as_secs() as f64 + execution_duration.subsec_nanos() as f64 * 1e-9;
@@ -266,33 +494,216 @@ fn main() {
     if !quiet_mode {
       println!("Done.");
     }
+
+    if let StepResult::Faulted(fault) = result {
+      io::stdout().flush().unwrap();
+      process::exit(fault.kind.code() as i32);
+    }
   } else {
-    let mut out_file: File;
-    let output_file_path: &Path = Path::new(&output_file);
+    write_object(&object, &output_file, raw_mode, quiet_mode);
+  }
+}
+
+/// Writes an object to a file, or just its memory image in raw mode
+fn write_object(object: &ObjectFile, output_file: &str, raw_mode: bool, quiet_mode: bool) {
+  if raw_mode && !object.imports.is_empty() {
+    println!("The program imports symbols, link it before writing a raw memory image");
+    return;
+  }
+
+  let mut out_file: File;
+  let output_file_path: &Path = Path::new(output_file);
+
+  match File::create(output_file_path) {
+    Ok(_file) => out_file = _file,
+    Err(err) => {
+      match err.kind() {
+        ErrorKind::PermissionDenied => println!("Output file access denied"),
+        _ => println!("Error opening file, {:?}", err),
+      }
+      return;
+    }
+  }
+
+  if !quiet_mode {
+    print!("Writing file... ");
+  }
+
+  let output_bytes: Vec<u8> = if raw_mode { object.to_raw() } else { object.to_bytes() };
+
+  match out_file.write_all(&output_bytes) {
+    Ok(..) => if !quiet_mode {
+      println!("Done.");
+    },
+    Err(err) => println!("Error writing file, {:?}", err),
+  }
+}
+
+/// Writes the assembler's listing to a file
+fn write_listing(listing: &str, listing_file: &str, quiet_mode: bool) {
+  let mut out_file: File;
+
+  match File::create(Path::new(listing_file)) {
+    Ok(_file) => out_file = _file,
+    Err(err) => {
+      match err.kind() {
+        ErrorKind::PermissionDenied => println!("Listing file access denied"),
+        _ => println!("Error opening listing file, {:?}", err),
+      }
+      return;
+    }
+  }
+
+  if !quiet_mode {
+    print!("Writing listing... ");
+  }
+
+  match out_file.write_all(listing.as_bytes()) {
+    Ok(..) => if !quiet_mode {
+      println!("Done.");
+    },
+    Err(err) => println!("Error writing listing, {:?}", err),
+  }
+}
 
-    match File::create(output_file_path) {
-      Ok(_file) => out_file = _file,
+/// Reads an object file for linking
+fn read_object(path: &str) -> Result<ObjectFile, String> {
+  let mut file_bytes: Vec<u8> = Vec::new();
+  match File::open(Path::new(path)) {
+    Ok(mut file) => {
+      if let Err(err) = file.read_to_end(&mut file_bytes) {
+        return Err(format!("Error reading file '{}', {:?}", path, err));
+      }
+    }
+    Err(err) => {
+      return match err.kind() {
+        ErrorKind::NotFound => Err(format!("Input file '{}' does not exist", path)),
+        ErrorKind::PermissionDenied => Err(format!("Input file '{}' access denied", path)),
+        _ => Err(format!("Error opening file '{}', {:?}", path, err)),
+      };
+    }
+  }
+  if !ObjectFile::is_object(&file_bytes) {
+    return Err(format!("'{}' is not an object file, assemble it with -a first", path));
+  }
+  return ObjectFile::from_bytes(&file_bytes).map_err(|err| format!("{}: {}", path, err));
+}
+
+/// Links the input objects (and any needed libraries) into one object
+fn link_objects(args: &Args, output_file: &str, raw_mode: bool, quiet_mode: bool) {
+  let mut linker: Linker = Linker::new();
+
+  if let Some(arg) = args.get_arg("-org") {
+    match linker::parse_address(&arg.value) {
+      Some(origin) => linker.set_origin(origin),
+      None => {
+        println!("Invalid origin specified, \"{}\"", arg.value);
+        return;
+      }
+    }
+  }
+
+  let mut placements: Vec<(String, Option<u16>)> = Vec::new();
+  if let Some(arg) = args.get_arg("-ls") {
+    let mut script: String = String::new();
+    match File::open(Path::new(&arg.value)) {
+      Ok(mut file) => {
+        if let Err(err) = file.read_to_string(&mut script) {
+          println!("Error reading linker script, {:?}", err);
+          return;
+        }
+      }
       Err(err) => {
-        match err.kind() {
-          ErrorKind::PermissionDenied => println!("Output file access denied"),
-          _ => println!("Error opening file, {:?}", err),
+        println!("Error opening linker script '{}', {:?}", arg.value, err.kind());
+        return;
+      }
+    }
+    match linker::parse_script(Path::new(&arg.value), &script) {
+      Ok(linker_script) => {
+        placements = linker_script.placements;
+        if let Some(entry) = linker_script.entry {
+          linker.set_entry(&entry);
         }
+      }
+      Err(err) => {
+        println!("{}", err);
         return;
       }
     }
+  }
+  for arg in args.get_args("") {
+    placements.push((arg.value.to_string(), None));
+  }
+  if placements.is_empty() {
+    println!("Linking requires at least one object file");
+    return;
+  }
 
-    if !quiet_mode {
-      print!("Writing file... ");
-    }
+  if !quiet_mode {
+    print!("Linking files... ");
+  }
 
-    match out_file.write(&bytecode) {
-      Ok(..) => if !quiet_mode {
-        println!("Done.");
-      },
+  for (path, address) in placements {
+    match read_object(&path) {
+      Ok(object) => linker.add_object(&path, object, address),
       Err(err) => {
-        println!("Error writing file, {:?}", err);
+        println!("Failed!");
+        println!("{}", err);
         return;
       }
     }
   }
+  for arg in args.get_args("-lib") {
+    match read_object(&arg.value) {
+      Ok(object) => linker.add_library(&arg.value, object),
+      Err(err) => {
+        println!("Failed!");
+        println!("{}", err);
+        return;
+      }
+    }
+  }
+
+  match linker.link() {
+    Ok(object) => {
+      if !quiet_mode {
+        println!("Done.");
+      }
+      write_object(&object, output_file, raw_mode, quiet_mode);
+    }
+    Err(errors) => {
+      println!("Failed!");
+      for error in errors.iter() {
+        println!("{}", error);
+      }
+    }
+  }
+}
+
+/// Parses an address, such as a trap handler's, written as an address or :LABEL
+fn parse_location(source_map: &SourceMap, to_parse: &str) -> Option<u16> {
+  match to_parse.strip_prefix(':') {
+    Some(name) => source_map.symbol_address(name),
+    None => linker::parse_address(to_parse),
+  }
+}
+
+/// Parses a fault policy, written as NAME=ignore, NAME=halt or NAME=ADDRESS to trap to a handler
+fn parse_fault_policy(source_map: &SourceMap, to_parse: &str) -> Result<(FaultKind, FaultPolicy), String> {
+  let (name, policy) = match to_parse.split_once('=') {
+    Some(parts) => parts,
+    None => return Err(format!("Invalid fault policy \"{}\", expected NAME=POLICY", to_parse)),
+  };
+  let kind: FaultKind = match FaultKind::from_name(name) {
+    Some(kind) => kind,
+    None => return Err(format!("Unknown fault \"{}\"", name)),
+  };
+  match policy {
+    "ignore" => return Ok((kind, FaultPolicy::Ignore)),
+    "halt" => return Ok((kind, FaultPolicy::Halt)),
+    _ => match parse_location(source_map, policy) {
+      Some(address) => return Ok((kind, FaultPolicy::Trap(address))),
+      None => return Err(format!("Invalid trap handler \"{}\"", policy)),
+    },
+  }
 }
@@ -0,0 +1,426 @@
+//! Module for the Jello object file format
+//!
+//! An object file starts with a 12 byte header, all values are little endian:
+//!
+//!   - magic "JELO" (4 bytes)
+//!   - format version (2 bytes)
+//!   - instruction set revision (2 bytes)
+//!   - entry point (2 bytes)
+//!   - section count (2 bytes)
+//!
+//! Followed by the sections, each being a kind (1 byte), a payload length (4 bytes) and the
+//! payload. Sections of an unknown kind are skipped so newer files can add optional sections.
+//!
+//! Objects are relocatable, the relocation section lists the 16-bit values which hold addresses
+//! within the object and the import section lists values which hold the address of a symbol
+//! exported by another object, both of which the linker adjusts when it places the object. Values
+//! which depend on an address in a way that can't be adjusted (such as the low byte of a label)
+//! are listed in the fixed section, objects with any of these can only be placed at address 0.
+//! Version 2 added the relocation, export, import and fixed sections.
+//!
+//! The file and source line sections map addresses back to the source they were assembled from,
+//! version 3 added the file section and replaced the line section (which is still read, with every
+//! line in an unnamed file) with the source line section.
+
+use instructions;
+use machine::Machine;
+
+pub const MAGIC: [u8; 4] = [b'J', b'E', b'L', b'O'];
+pub const FORMAT_VERSION: u16 = 3;
+
+const HEADER_SIZE: usize = 12;
+
+/// Payload: load address (2 bytes) followed by the bytes to load
+const SECTION_SEGMENT: u8 = 0x01;
+/// Payload: repeated address (2 bytes), name length (2 bytes), name
+const SECTION_SYMBOLS: u8 = 0x02;
+/// Payload: repeated address (2 bytes), line (4 bytes), only written before version 3
+const SECTION_LINES: u8 = 0x03;
+/// Payload: repeated address (2 bytes) of a value to add the object's base address to
+const SECTION_RELOCATIONS: u8 = 0x04;
+/// Payload: same as the symbols section
+const SECTION_EXPORTS: u8 = 0x05;
+/// Payload: same as the symbols section, each address is a value to add the symbol's address to
+const SECTION_IMPORTS: u8 = 0x06;
+/// Payload: repeated address (2 bytes) of a value which can't be relocated
+const SECTION_FIXED: u8 = 0x07;
+/// Payload: repeated name length (2 bytes), name, in the order line entries refer to them
+const SECTION_FILES: u8 = 0x08;
+/// Payload: repeated address (2 bytes), file index (2 bytes), line (4 bytes)
+const SECTION_SOURCE_LINES: u8 = 0x09;
+
+#[derive(Clone, Debug)]
+pub struct Segment {
+  pub load_address: u16,
+  pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+  pub name: String,
+  pub address: u16,
+}
+
+/// Maps the code starting at an address back to the source line it came from, the file is an
+/// index into the object's files
+#[derive(Clone, Debug)]
+pub struct LineEntry {
+  pub address: u16,
+  pub file: u16,
+  pub line: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ObjectFile {
+  pub version: u16,
+  pub isa_revision: u16,
+  pub entry_point: u16,
+  pub segments: Vec<Segment>,
+  pub symbols: Vec<Symbol>,
+  pub lines: Vec<LineEntry>,
+  pub relocations: Vec<u16>,
+  pub exports: Vec<Symbol>,
+  pub imports: Vec<Symbol>,
+  pub fixed: Vec<u16>,
+  pub files: Vec<String>,
+}
+
+impl Default for ObjectFile {
+  fn default() -> ObjectFile {
+    return ObjectFile::new();
+  }
+}
+
+impl ObjectFile {
+  pub fn new() -> ObjectFile {
+    return ObjectFile {
+      version: FORMAT_VERSION,
+      isa_revision: instructions::ISA_REVISION,
+      entry_point: 0,
+      segments: Vec::new(),
+      symbols: Vec::new(),
+      lines: Vec::new(),
+      relocations: Vec::new(),
+      exports: Vec::new(),
+      imports: Vec::new(),
+      fixed: Vec::new(),
+      files: Vec::new(),
+    };
+  }
+}
+
+/// Wraps a legacy headerless dump, which is always loaded at address 0
+impl ObjectFile {
+  pub fn from_raw(bytes: Vec<u8>) -> ObjectFile {
+    let mut result: ObjectFile = ObjectFile::new();
+    result.segments.push(Segment {
+      load_address: 0,
+      bytes,
+    });
+    return result;
+  }
+}
+
+/// Checks for the object file magic at the start of some bytes
+impl ObjectFile {
+  pub fn is_object(bytes: &[u8]) -> bool {
+    bytes.len() >= MAGIC.len() && bytes[..MAGIC.len()] == MAGIC
+  }
+}
+
+impl ObjectFile {
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut sections: Vec<(u8, Vec<u8>)> = Vec::new();
+
+    for segment in self.segments.iter() {
+      let mut payload: Vec<u8> = Vec::new();
+      push_u16(&mut payload, segment.load_address);
+      payload.extend_from_slice(&segment.bytes);
+      sections.push((SECTION_SEGMENT, payload));
+    }
+
+    if !self.symbols.is_empty() {
+      sections.push((SECTION_SYMBOLS, symbols_payload(&self.symbols)));
+    }
+
+    if !self.files.is_empty() {
+      let mut payload: Vec<u8> = Vec::new();
+      for file in self.files.iter() {
+        push_u16(&mut payload, file.len() as u16);
+        payload.extend_from_slice(file.as_bytes());
+      }
+      sections.push((SECTION_FILES, payload));
+    }
+
+    if !self.lines.is_empty() {
+      let mut payload: Vec<u8> = Vec::new();
+      for entry in self.lines.iter() {
+        push_u16(&mut payload, entry.address);
+        push_u16(&mut payload, entry.file);
+        push_u32(&mut payload, entry.line);
+      }
+      sections.push((SECTION_SOURCE_LINES, payload));
+    }
+
+    if !self.relocations.is_empty() {
+      sections.push((SECTION_RELOCATIONS, addresses_payload(&self.relocations)));
+    }
+    if !self.exports.is_empty() {
+      sections.push((SECTION_EXPORTS, symbols_payload(&self.exports)));
+    }
+    if !self.imports.is_empty() {
+      sections.push((SECTION_IMPORTS, symbols_payload(&self.imports)));
+    }
+    if !self.fixed.is_empty() {
+      sections.push((SECTION_FIXED, addresses_payload(&self.fixed)));
+    }
+
+    let mut result: Vec<u8> = Vec::new();
+    result.extend_from_slice(&MAGIC);
+    push_u16(&mut result, self.version);
+    push_u16(&mut result, self.isa_revision);
+    push_u16(&mut result, self.entry_point);
+    push_u16(&mut result, sections.len() as u16);
+    for (kind, payload) in sections {
+      result.push(kind);
+      push_u32(&mut result, payload.len() as u32);
+      result.extend(payload);
+    }
+    return result;
+  }
+}
+
+impl ObjectFile {
+  pub fn from_bytes(bytes: &[u8]) -> Result<ObjectFile, String> {
+    if !ObjectFile::is_object(bytes) {
+      return Err("Object Format Error: missing magic number".to_string());
+    }
+    if bytes.len() < HEADER_SIZE {
+      return Err("Object Format Error: truncated header".to_string());
+    }
+
+    let mut result: ObjectFile = ObjectFile::new();
+    result.version = read_u16(bytes, 4);
+    result.isa_revision = read_u16(bytes, 6);
+    result.entry_point = read_u16(bytes, 8);
+    let section_count: u16 = read_u16(bytes, 10);
+
+    if result.version > FORMAT_VERSION {
+      return Err(format!(
+        "Object Format Error: format version {} is newer than supported version {}",
+        result.version,
+        FORMAT_VERSION
+      ));
+    }
+    if result.isa_revision > instructions::ISA_REVISION {
+      return Err(format!(
+        "Object Format Error: targets instruction set revision {} but only revision {} is supported",
+        result.isa_revision,
+        instructions::ISA_REVISION
+      ));
+    }
+
+    let mut position: usize = HEADER_SIZE;
+    for _ in 0..section_count {
+      if position + 5 > bytes.len() {
+        return Err("Object Format Error: truncated section header".to_string());
+      }
+      let kind: u8 = bytes[position];
+      let length: usize = read_u32(bytes, position + 1) as usize;
+      position += 5;
+      if position + length > bytes.len() {
+        return Err("Object Format Error: truncated section".to_string());
+      }
+      let payload: &[u8] = &bytes[position..position + length];
+      position += length;
+
+      match kind {
+        SECTION_SEGMENT => {
+          if payload.len() < 2 {
+            return Err("Object Format Error: truncated segment".to_string());
+          }
+          let load_address: u16 = read_u16(payload, 0);
+          if load_address as usize + payload.len() - 2 > 65536 {
+            return Err(format!(
+              "Object Format Error: segment at 0x{:04x} does not fit in memory",
+              load_address
+            ));
+          }
+          result.segments.push(Segment {
+            load_address,
+            bytes: payload[2..].to_vec(),
+          });
+        }
+        SECTION_SYMBOLS => result.symbols = read_symbols(payload)?,
+        SECTION_LINES => {
+          if !payload.len().is_multiple_of(6) {
+            return Err("Object Format Error: truncated line table".to_string());
+          }
+          for entry in payload.chunks(6) {
+            result.lines.push(LineEntry {
+              address: read_u16(entry, 0),
+              file: 0,
+              line: read_u32(entry, 2),
+            });
+          }
+        }
+        SECTION_FILES => {
+          let mut file_position: usize = 0;
+          while file_position < payload.len() {
+            if file_position + 2 > payload.len() {
+              return Err("Object Format Error: truncated file table".to_string());
+            }
+            let name_length: usize = read_u16(payload, file_position) as usize;
+            file_position += 2;
+            if file_position + name_length > payload.len() {
+              return Err("Object Format Error: truncated file table".to_string());
+            }
+            result
+              .files
+              .push(String::from_utf8_lossy(&payload[file_position..file_position + name_length]).to_string());
+            file_position += name_length;
+          }
+        }
+        SECTION_SOURCE_LINES => {
+          if !payload.len().is_multiple_of(8) {
+            return Err("Object Format Error: truncated line table".to_string());
+          }
+          for entry in payload.chunks(8) {
+            result.lines.push(LineEntry {
+              address: read_u16(entry, 0),
+              file: read_u16(entry, 2),
+              line: read_u32(entry, 4),
+            });
+          }
+        }
+        SECTION_RELOCATIONS => result.relocations = read_addresses(payload, "relocation")?,
+        SECTION_EXPORTS => result.exports = read_symbols(payload)?,
+        SECTION_IMPORTS => result.imports = read_symbols(payload)?,
+        SECTION_FIXED => result.fixed = read_addresses(payload, "fixed address")?,
+        _ => {}
+      }
+    }
+
+    return Ok(result);
+  }
+}
+
+/// Flattens the segments into a legacy headerless dump starting at address 0
+impl ObjectFile {
+  pub fn to_raw(&self) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::new();
+    for segment in self.segments.iter() {
+      let start: usize = segment.load_address as usize;
+      let end: usize = start + segment.bytes.len();
+      if result.len() < end {
+        result.resize(end, 0);
+      }
+      result[start..end].copy_from_slice(&segment.bytes);
+    }
+    return result;
+  }
+}
+
+/// Where the object ends, which is how much space it takes up when placed at address 0
+impl ObjectFile {
+  pub fn extent(&self) -> usize {
+    let mut result: usize = 0;
+    for segment in self.segments.iter() {
+      result = result.max(segment.load_address as usize + segment.bytes.len());
+    }
+    return result;
+  }
+}
+
+/// Copies the segments into memory and points the machine at the entry point
+impl ObjectFile {
+  pub fn load_into(&self, machine: &mut Machine) -> Result<(), String> {
+    if !self.imports.is_empty() {
+      let mut names: Vec<&str> = self.imports.iter().map(|symbol| symbol.name.as_str()).collect();
+      names.sort();
+      names.dedup();
+      return Err(format!(
+        "Load Error: the program imports symbols which haven't been linked ({})",
+        names.join(", ")
+      ));
+    }
+    for segment in self.segments.iter() {
+      let start: usize = segment.load_address as usize;
+      let end: usize = start + segment.bytes.len();
+      if end > machine.memory.len() {
+        return Err(format!(
+          "Load Error: segment at 0x{:04x} does not fit in memory",
+          segment.load_address
+        ));
+      }
+      machine.memory[start..end].copy_from_slice(&segment.bytes);
+    }
+    machine.instruction_pointer = self.entry_point;
+    return Ok(());
+  }
+}
+
+fn symbols_payload(symbols: &[Symbol]) -> Vec<u8> {
+  let mut payload: Vec<u8> = Vec::new();
+  for symbol in symbols.iter() {
+    push_u16(&mut payload, symbol.address);
+    push_u16(&mut payload, symbol.name.len() as u16);
+    payload.extend_from_slice(symbol.name.as_bytes());
+  }
+  return payload;
+}
+
+fn read_symbols(payload: &[u8]) -> Result<Vec<Symbol>, String> {
+  let mut result: Vec<Symbol> = Vec::new();
+  let mut symbol_position: usize = 0;
+  while symbol_position < payload.len() {
+    if symbol_position + 4 > payload.len() {
+      return Err("Object Format Error: truncated symbol".to_string());
+    }
+    let address: u16 = read_u16(payload, symbol_position);
+    let name_length: usize = read_u16(payload, symbol_position + 2) as usize;
+    symbol_position += 4;
+    if symbol_position + name_length > payload.len() {
+      return Err("Object Format Error: truncated symbol".to_string());
+    }
+    let name: String =
+      String::from_utf8_lossy(&payload[symbol_position..symbol_position + name_length])
+        .to_string();
+    symbol_position += name_length;
+    result.push(Symbol { name, address });
+  }
+  return Ok(result);
+}
+
+fn addresses_payload(addresses: &[u16]) -> Vec<u8> {
+  let mut payload: Vec<u8> = Vec::new();
+  for address in addresses.iter() {
+    push_u16(&mut payload, *address);
+  }
+  return payload;
+}
+
+fn read_addresses(payload: &[u8], description: &str) -> Result<Vec<u16>, String> {
+  if !payload.len().is_multiple_of(2) {
+    return Err(format!("Object Format Error: truncated {} table", description));
+  }
+  return Ok(payload.chunks(2).map(|entry| read_u16(entry, 0)).collect());
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+  bytes.push((value & 0xff) as u8);
+  bytes.push((value >> 8) as u8);
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+  push_u16(bytes, (value & 0xffff) as u16);
+  push_u16(bytes, (value >> 16) as u16);
+}
+
+fn read_u16(bytes: &[u8], position: usize) -> u16 {
+  (bytes[position] as u16) | ((bytes[position + 1] as u16) << 8)
+}
+
+fn read_u32(bytes: &[u8], position: usize) -> u32 {
+  (read_u16(bytes, position) as u32) | ((read_u16(bytes, position + 2) as u32) << 16)
+}
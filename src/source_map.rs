@@ -0,0 +1,88 @@
+//! Module for mapping addresses back to the source they were assembled from, using the line table
+//! and symbols of an object file
+
+use object_file::{LineEntry, ObjectFile, Symbol};
+
+pub struct SourceMap {
+  files: Vec<String>,
+  lines: Vec<LineEntry>,
+  symbols: Vec<Symbol>,
+}
+
+impl SourceMap {
+  pub fn new(object: &ObjectFile) -> SourceMap {
+    let mut lines: Vec<LineEntry> = object.lines.clone();
+    lines.sort_by_key(|entry| entry.address);
+    return SourceMap {
+      files: object.files.clone(),
+      lines,
+      symbols: object.symbols.clone(),
+    };
+  }
+}
+
+impl SourceMap {
+  pub fn symbols(&self) -> &Vec<Symbol> {
+    &self.symbols
+  }
+}
+
+/// Finds the address of a label by name
+impl SourceMap {
+  pub fn symbol_address(&self, name: &str) -> Option<u16> {
+    return self.symbols.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.address);
+  }
+}
+
+/// Finds the file and line the code at an address was assembled from, the file is empty if the
+/// source didn't come from a file
+impl SourceMap {
+  pub fn line_at(&self, address: u16) -> Option<(&str, u32)> {
+    let entry: &LineEntry = self.lines.iter().rev().find(|entry| entry.address <= address)?;
+    let file: &str = self.files.get(entry.file as usize).map(|file| file.as_str()).unwrap_or("");
+    return Some((file, entry.line));
+  }
+}
+
+/// Finds the closest label at or before an address
+impl SourceMap {
+  pub fn label_at(&self, address: u16) -> Option<&Symbol> {
+    let mut closest: Option<&Symbol> = None;
+    for symbol in self.symbols.iter() {
+      if symbol.address <= address {
+        match closest {
+          Some(current) if current.address >= symbol.address => {}
+          _ => closest = Some(symbol),
+        }
+      }
+    }
+    return closest;
+  }
+}
+
+/// Describes an address relative to the closest label at or before it, such as :PRINT+3
+impl SourceMap {
+  pub fn describe_address(&self, address: u16) -> String {
+    match self.label_at(address) {
+      Some(symbol) if symbol.address == address => format!(":{}", symbol.name),
+      Some(symbol) => format!(":{}+{}", symbol.name, address - symbol.address),
+      None => format!("0x{:04x}", address),
+    }
+  }
+}
+
+/// Describes where the code at an address came from, such as word-wrap.jello:42 in :PRINT, or
+/// just relative to a label if there is no line information
+impl SourceMap {
+  pub fn describe(&self, address: u16) -> String {
+    let line: String = match self.line_at(address) {
+      Some(("", line)) => format!("line {}", line),
+      Some((file, line)) => format!("{}:{}", file, line),
+      None => return self.describe_address(address),
+    };
+    match self.label_at(address) {
+      Some(symbol) => format!("{} in :{}", line, symbol.name),
+      None => line,
+    }
+  }
+}
@@ -0,0 +1,64 @@
+//! Module for the timer, a device which raises an interrupt line every so many clock cycles
+//!
+//! It takes 3 bytes of memory: the period in clock cycles as a little endian 16-bit value at offsets
+//! 0 and 1, and a control byte at offset 2 where bit 0 starts (1) or stops (0) the timer. Writing
+//! the control byte starts the count again from the full period.
+
+use bus::Device;
+use interrupts::InterruptController;
+
+/// Number of bytes of memory the timer takes
+pub const TIMER_LENGTH: usize = 3;
+
+pub struct Timer {
+  line: u8,
+  period: u16,
+  running: bool,
+  remaining: u64,
+}
+
+impl Timer {
+  pub fn new(line: u8) -> Timer {
+    return Timer {
+      line,
+      period: 0,
+      running: false,
+      remaining: 0,
+    };
+  }
+}
+
+impl Device for Timer {
+  fn read(&mut self, offset: u16) -> u8 {
+    match offset {
+      0 => (self.period & 0xff) as u8,
+      1 => (self.period >> 8) as u8,
+      _ => self.running as u8,
+    }
+  }
+
+  fn write(&mut self, offset: u16, value: u8) {
+    match offset {
+      0 => self.period = (self.period & 0xff00) | value as u16,
+      1 => self.period = (self.period & 0x00ff) | ((value as u16) << 8),
+      _ => {
+        self.running = value & 1 != 0;
+        self.remaining = self.period as u64;
+      }
+    }
+  }
+
+  fn tick(&mut self, cycles: u64, interrupts: &mut InterruptController) {
+    if !self.running || self.period == 0 {
+      return;
+    }
+    if cycles < self.remaining {
+      self.remaining -= cycles;
+      return;
+    }
+    // Periods which went by while the line was already raised are lost, as on real hardware
+    let period: u64 = self.period as u64;
+    self.remaining = period - (cycles - self.remaining) % period;
+    interrupts.raise(self.line);
+  }
+}
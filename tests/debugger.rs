@@ -0,0 +1,68 @@
+//! Tests driving the command line debugger, feeding it commands on stdin
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+/// Runs an example in the debugger with the given arguments and commands, returning everything it printed
+fn debug_example(name: &str, args: &[&str], commands: &str) -> String {
+  // Run from the crate so the debugger describes locations with a path relative to it
+  let mut child = Command::new(env!("CARGO_BIN_EXE_rusty_jello"))
+    .current_dir(env!("CARGO_MANIFEST_DIR"))
+    .arg(Path::new("examples").join(name))
+    .arg("-dbg")
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .unwrap();
+  child.stdin.take().unwrap().write_all(commands.as_bytes()).unwrap();
+  let output: Output = child.wait_with_output().unwrap();
+  assert!(output.status.success());
+  return String::from_utf8(output.stdout).unwrap();
+}
+
+#[test]
+fn runs_to_breakpoints_and_shows_state() {
+  let output: String = debug_example("fib.jello", &[], "break :END\ncontinue\nregs\nset r2 0x12\nr\nstack\nmem :END 4\nquit\n");
+  assert!(output.contains("(jello) Breakpoint set at :END\n"), "{}", output);
+  assert!(output.contains("Breakpoint hit\nexamples/fib.jello:13 in :END [0011] DROP"), "{}", output);
+  assert!(output.contains("acc: 2511, ip: 0011, r0: 0000, r1: 0000, r2: 0000, r3: 0000\n"), "{}", output);
+  assert!(output.contains("acc: 2511, ip: 0011, r0: 0000, r1: 0000, r2: 0012, r3: 0000\n"), "{}", output);
+  assert!(output.contains("stack: {2511 b520}\n"), "{}", output);
+  assert!(output.contains("[0011] 35 f5 08 00\n"), "{}", output);
+}
+
+#[test]
+fn repeats_the_last_command_on_an_empty_line() {
+  let output: String = debug_example("fib.jello", &[], "step 2\n\nwhere\n");
+  assert!(output.contains("(jello) examples/fib.jello:5 in :LOOP [0006] DUP"), "{}", output);
+  assert!(output.contains("(jello) examples/fib.jello:7 in :LOOP [0008] ADD"), "{}", output);
+}
+
+#[test]
+fn rejects_malformed_commands() {
+  let output: String = debug_example("fib.jello", &[], "bogus\nstep x\nbreak\nb :NOPE\nmem 0 y\nset pc 1\npoke 0 0x100\n");
+  assert!(output.contains("Unknown command \"bogus\", type \"help\" for a list of commands\n"), "{}", output);
+  assert!(output.contains("Invalid step count \"x\"\n"), "{}", output);
+  assert!(output.contains("Usage: break LOCATION\n"), "{}", output);
+  assert!(output.contains("Unknown location \":NOPE\"\n"), "{}", output);
+  assert!(output.contains("Invalid length \"y\"\n"), "{}", output);
+  assert!(output.contains("Unknown name \"pc\"\n"), "{}", output);
+  assert!(output.contains("Invalid byte \"0x100\"\n"), "{}", output);
+}
+
+#[test]
+fn reads_and_writes_memory_through_devices() {
+  // The timer's control byte only keeps bit 0, so the 3 written to it reads back as 1
+  let output: String = debug_example("fib.jello", &["-timer", "0xff00"], "poke 0xff00 0x34 0x12 3\nmem 0xff00 3\n");
+  assert!(output.contains("[ff00] 34 12 01\n"), "{}", output);
+}
+
+#[test]
+fn stops_at_an_ignored_illegal_instruction() {
+  let output: String =
+    debug_example("fib.jello", &["-fault", "illegal-instruction=ignore"], "poke :END 0xff\ncontinue\nregs\n");
+  assert!(output.contains("Illegal instruction ignored\nexamples/fib.jello:13 in :END Unknown Instruction ff"), "{}", output);
+  assert!(output.contains("ip: 0011"), "{}", output);
+}
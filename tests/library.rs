@@ -0,0 +1,868 @@
+//! Integration tests driving the assembler, linker and machine only through the library
+
+extern crate rusty_jello;
+
+use rusty_jello::diagnostic::DiagnosticKind;
+use rusty_jello::expression::{self, Expression};
+use rusty_jello::interrupts::{SERIAL_INPUT_LINE, TIMER_LINE};
+use rusty_jello::timer::TIMER_LENGTH;
+use rusty_jello::{
+  find_inst_by_name, find_inst_by_opcode, Assembler, Device, Diagnostic, Disassembler, FaultKind, FaultPolicy,
+  InterruptController, Linker, Machine, ObjectFile, SourceMap, Stack, StepResult, Timer, INSTRUCTIONS,
+};
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Most instructions a program is allowed before it is treated as stuck
+const STEP_LIMIT: usize = 1_000_000;
+
+fn example_path(name: &str) -> PathBuf {
+  return Path::new(env!("CARGO_MANIFEST_DIR")).join("examples").join(name);
+}
+
+fn assemble(source: &str) -> Result<ObjectFile, Vec<Diagnostic>> {
+  let mut assembler: Assembler = Assembler::new();
+  assembler.add_string(source);
+  return assembler.assemble_object();
+}
+
+fn assemble_example(name: &str) -> ObjectFile {
+  let path: PathBuf = example_path(name);
+  let source: String = fs::read_to_string(&path).unwrap();
+  let mut assembler: Assembler = Assembler::new();
+  assembler.add_file_string(&path, source.as_str());
+  match assembler.assemble_object() {
+    Ok(object) => return object,
+    Err(diagnostics) => panic!("{} failed to assemble, {}", name, diagnostics[0]),
+  }
+}
+
+fn load(object: &ObjectFile) -> Machine {
+  let mut machine: Machine = Machine::new();
+  object.load_into(&mut machine).unwrap();
+  return machine;
+}
+
+/// Steps the machine until it halts, returning everything it output
+fn run(machine: &mut Machine) -> String {
+  let mut output: String = String::new();
+  for _ in 0..STEP_LIMIT {
+    if machine.flags.halt {
+      return output;
+    }
+    machine.step();
+    for byte in machine.output_buffer.take_all() {
+      output.push(byte as char);
+    }
+  }
+  panic!("machine didn't halt within {} steps, output so far {:?}", STEP_LIMIT, output);
+}
+
+#[test]
+fn runs_fib_example() {
+  let mut machine: Machine = load(&assemble_example("fib.jello"));
+  assert_eq!(run(&mut machine), "0xb520");
+  assert_eq!(machine.fault, None);
+}
+
+#[test]
+fn runs_print_example() {
+  let mut machine: Machine = load(&assemble_example("print-test.jello"));
+  assert_eq!(run(&mut machine), "Hello World. Hello World!");
+}
+
+#[test]
+fn echoes_input_from_the_input_buffer() {
+  let mut machine: Machine = load(&assemble_example("input-test.jello"));
+  machine.input_buffer.put_string("Jello, World".to_string());
+  machine.input_buffer.close();
+  assert_eq!(run(&mut machine), "JELLO, WORLD");
+}
+
+#[test]
+fn waits_for_input_until_the_buffer_has_bytes() {
+  let mut machine: Machine = load(&assemble("INP\nPRN\nHALT").unwrap());
+  assert_eq!(machine.step(), StepResult::WaitingForInput);
+  assert!(machine.waiting_for_input);
+  assert_eq!(machine.instruction_pointer, 0);
+  machine.input_buffer.put(b'x');
+  assert_eq!(run(&mut machine), "x");
+  assert!(!machine.waiting_for_input);
+}
+
+#[test]
+fn steps_report_why_they_stopped() {
+  let mut machine: Machine = load(&assemble("NOOP\nNOOP\n.BYTE 0xfe").unwrap());
+  machine.breakpoints.push(2);
+  assert_eq!(machine.step(), StepResult::Executed);
+  assert_eq!(machine.step(), StepResult::Breakpoint(2));
+  let fault = match machine.step() {
+    StepResult::Faulted(fault) => fault,
+    other => panic!("expected a fault, got {:?}", other),
+  };
+  assert_eq!(fault.kind, FaultKind::IllegalInstruction);
+  assert_eq!(machine.step(), StepResult::Faulted(fault));
+
+  let mut machine: Machine = load(&assemble("HALT").unwrap());
+  assert_eq!(machine.step(), StepResult::Halted);
+  assert_eq!(machine.step(), StepResult::Halted);
+}
+
+#[test]
+fn runs_for_a_budget_of_cycles() {
+  let mut machine: Machine = load(&assemble(":LOOP\nJMPI :LOOP").unwrap());
+  let cycles: u64 = find_inst_by_name("JMPI").unwrap().clock_cycles as u64;
+  assert_eq!(machine.run_for(10 * cycles), StepResult::Executed);
+  assert_eq!(machine.cycles, 10 * cycles);
+  assert_eq!(machine.run_for(5 * cycles), StepResult::Executed);
+  assert_eq!(machine.cycles, 15 * cycles);
+
+  let mut machine: Machine = load(&assemble_example("fib.jello"));
+  assert_eq!(machine.run_for(u64::MAX), StepResult::Halted);
+  assert_eq!(machine.output_buffer.take_all(), b"0xb520".to_vec());
+}
+
+#[test]
+fn runs_until_a_condition_holds() {
+  let mut machine: Machine = load(&assemble("PRNI 'a'\nPRNI 'b'\nPRNI 'c'\nHALT").unwrap());
+  let result: StepResult = machine.run_until(u64::MAX, |machine: &Machine| machine.output_buffer.len() == 2);
+  assert_eq!(result, StepResult::Stopped);
+  assert_eq!(machine.output_buffer.take_all(), b"ab".to_vec());
+  assert_eq!(machine.run_until(u64::MAX, |_: &Machine| false), StepResult::Halted);
+  assert_eq!(machine.output_buffer.take_all(), b"c".to_vec());
+
+  // Running out of cycles before the condition holds gives Executed instead
+  let mut machine: Machine = load(&assemble(":LOOP\nPRNI 'a'\nJMPI :LOOP").unwrap());
+  let cycles: u64 = find_inst_by_name("PRNI").unwrap().clock_cycles as u64;
+  let result: StepResult = machine.run_until(cycles, |machine: &Machine| machine.output_buffer.len() == 2);
+  assert_eq!(result, StepResult::Executed);
+  assert_eq!(machine.output_buffer.len(), 1);
+  let result: StepResult = machine.run_until(u64::MAX, |machine: &Machine| machine.output_buffer.len() == 2);
+  assert_eq!(result, StepResult::Stopped);
+}
+
+#[test]
+fn calls_registered_syscalls() {
+  let source: &str = "
+    PUSHI 0x0002
+    PUSHI 0x0003
+    SYSCALL 0x07
+    PUSH
+    DUMP8
+    SYSCALL 0x08
+    PRNI 'x'
+  ";
+  let mut machine: Machine = load(&assemble(source).unwrap());
+  machine.register_syscall(7, |machine: &mut Machine| {
+    let first: u16 = machine.stack.pop();
+    let second: u16 = machine.stack.pop();
+    machine.accumulator = first * second;
+  });
+  let calls: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+  let counted: Rc<Cell<usize>> = calls.clone();
+  machine.register_syscall(8, move |machine: &mut Machine| {
+    counted.set(counted.get() + 1);
+    machine.flags.halt = true;
+  });
+  assert_eq!(run(&mut machine), "0x06");
+  assert_eq!(calls.get(), 1);
+  assert_eq!(machine.fault, None);
+}
+
+#[test]
+fn faults_on_an_unknown_syscall() {
+  let mut machine: Machine = load(&assemble("SYSCALL 0x01\nPRNI 'x'\nHALT").unwrap());
+  assert_eq!(run(&mut machine), "");
+  assert_eq!(machine.fault.unwrap().kind, FaultKind::UnknownSyscall);
+
+  let mut machine: Machine = load(&assemble("SYSCALL 0x01\nPRNI 'x'\nHALT").unwrap());
+  machine.register_syscall(1, |_: &mut Machine| {});
+  assert!(machine.remove_syscall(1));
+  machine.set_fault_policy(FaultKind::UnknownSyscall, FaultPolicy::Ignore);
+  assert_eq!(run(&mut machine), "x");
+}
+
+#[test]
+fn faults_when_a_syscall_calls_itself() {
+  let mut machine: Machine = load(&assemble("SYSCALL 0x01\nPRNI 'x'\nHALT").unwrap());
+  machine.register_syscall(1, |machine: &mut Machine| {
+    machine.step();
+  });
+  assert_eq!(run(&mut machine), "");
+  assert_eq!(machine.fault.unwrap().kind, FaultKind::UnknownSyscall);
+}
+
+/// Device which records what is written to it and reads back how many cycles it has been ticked
+struct RecordingDevice {
+  writes: Rc<RefCell<Vec<(u16, u8)>>>,
+  cycles: u64,
+}
+
+impl Device for RecordingDevice {
+  fn read(&mut self, offset: u16) -> u8 {
+    return (self.cycles >> (8 * offset)) as u8;
+  }
+
+  fn write(&mut self, offset: u16, value: u8) {
+    self.writes.borrow_mut().push((offset, value));
+  }
+
+  fn tick(&mut self, cycles: u64, _interrupts: &mut InterruptController) {
+    self.cycles += cycles;
+  }
+}
+
+#[test]
+fn loads_and_stores_reach_mapped_devices() {
+  let source: &str = "
+    PUSHI 0x1234
+    STOREI 0x8001
+    PUSHI 0xbeef
+    STOREI 0x2000
+    LOADI 0x8000
+    DUMP16
+    LOADI 0x2000
+    DUMP16
+    HALT
+  ";
+  let mut machine: Machine = load(&assemble(source).unwrap());
+  let writes: Rc<RefCell<Vec<(u16, u8)>>> = Rc::new(RefCell::new(Vec::new()));
+  let device: RecordingDevice = RecordingDevice {
+    writes: writes.clone(),
+    cycles: 0,
+  };
+  machine.map_device(0x8000, 2, device).unwrap();
+
+  // Only the low byte lands in the device, the high byte falls just past it into RAM
+  let cycles_before_load: u64 = ["PUSHI", "STOREI", "PUSHI", "STOREI"]
+    .iter()
+    .map(|name| find_inst_by_name(name).unwrap().clock_cycles as u64)
+    .sum();
+  assert_eq!(run(&mut machine), format!("0x{:04x}0xbeef", cycles_before_load));
+  assert_eq!(*writes.borrow(), vec![(1, 0x34)]);
+  assert_eq!(machine.memory[0x8002], 0x12);
+  assert_eq!(machine.memory[0x8001], 0);
+}
+
+#[test]
+fn devices_cannot_overlap() {
+  let mut machine: Machine = Machine::new();
+  let device = || RecordingDevice {
+    writes: Rc::new(RefCell::new(Vec::new())),
+    cycles: 0,
+  };
+  machine.map_device(0x8000, 0x100, device()).unwrap();
+  assert!(machine.map_device(0x80ff, 1, device()).is_err());
+  assert!(machine.map_device(0xffff, 2, device()).is_err());
+  assert!(machine.map_device(0x8100, 1, device()).is_ok());
+  assert!(machine.map_device(0xffff, 1, device()).is_ok());
+}
+
+/// Sets up an interrupt handler for a line which runs the given code and counts its calls in R1,
+/// then waits with interrupts enabled until it has been called three times
+fn interrupt_program(line: u8, setup: &str, handler: &str) -> String {
+  return format!(
+    "
+    PUSHI :HANDLER
+    STOREI 0x{:04x}
+    {}
+    EI
+  :WAIT
+    LR1
+    PUSH
+    PUSHI 3
+    JMPIE :DONE
+    DROP
+    DROP
+    JMPI :WAIT
+  :DONE
+    HALT
+  :HANDLER
+    {}
+    LR1
+    PUSH
+    INC
+    SR1
+    RETI
+    ",
+    0xfff0 + line as u16 * 2,
+    setup,
+    handler
+  );
+}
+
+#[test]
+fn timer_interrupts_call_the_handler() {
+  let setup: &str = "
+    PUSHI 40
+    STOREI 0x8000
+    PUSHI 1
+    STOREI 0x8002
+  ";
+  let source: String = interrupt_program(TIMER_LINE, setup, "PRNI 'T'");
+  let mut machine: Machine = load(&assemble(source.as_str()).unwrap());
+  machine.map_device(0x8000, TIMER_LENGTH, Timer::new(TIMER_LINE)).unwrap();
+  assert_eq!(run(&mut machine), "TTT");
+  assert!(machine.cycles >= 3 * 40);
+}
+
+#[test]
+fn serial_input_interrupts_until_the_buffer_is_empty() {
+  let source: String = interrupt_program(SERIAL_INPUT_LINE, "", "INP\n    PRN");
+  let mut machine: Machine = load(&assemble(source.as_str()).unwrap());
+  machine.input_buffer.put_string("abc".to_string());
+  assert_eq!(run(&mut machine), "abc");
+  assert!(machine.input_buffer.is_empty());
+}
+
+#[test]
+fn interrupts_taken_while_waiting_for_input_run_the_handler() {
+  let source: &str = "
+    PUSHI :HANDLER
+    STOREI 0xfff2
+    PUSHI 10
+    STOREI 0x8000
+    PUSHI 1
+    STOREI 0x8002
+    EI
+    INP
+    PRN
+    HALT
+  :HANDLER
+    PRNI 'T'
+    PUSHI 0
+    STOREI 0x8002
+    RETI
+  ";
+  let object: ObjectFile = assemble(source).unwrap();
+  let handler: u16 = SourceMap::new(&object).symbol_address("HANDLER").unwrap();
+  let mut machine: Machine = load(&object);
+  machine.map_device(0x8000, TIMER_LENGTH, Timer::new(TIMER_LINE)).unwrap();
+
+  // The timer fires while INP waits, entering the handler isn't waiting for input
+  let mut result: StepResult = StepResult::Executed;
+  for _ in 0..100 {
+    if machine.instruction_pointer == handler {
+      break;
+    }
+    result = machine.step();
+  }
+  assert_eq!(machine.instruction_pointer, handler);
+  assert_eq!(result, StepResult::Executed);
+
+  // The whole handler runs before INP waits again
+  assert_eq!(machine.run_for(1000), StepResult::WaitingForInput);
+  assert_eq!(machine.output_buffer.take_all(), vec![b'T']);
+  machine.input_buffer.put_char('x');
+  assert_eq!(run(&mut machine), "x");
+}
+
+#[test]
+fn lines_without_a_handler_are_passed_over() {
+  let mut machine: Machine = load(&assemble("EI\nPRNI 'a'\nPRNI 'b'\nHALT").unwrap());
+  machine.input_buffer.put_char('x');
+  assert_eq!(machine.run_for(200), StepResult::Halted);
+  assert_eq!(machine.output_buffer.take_all(), vec![b'a', b'b']);
+
+  // The timer line is handled even though the serial input line below it is raised
+  let setup: &str = "
+    PUSHI 40
+    STOREI 0x8000
+    PUSHI 1
+    STOREI 0x8002
+  ";
+  let source: String = interrupt_program(TIMER_LINE, setup, "PRNI 'T'");
+  let mut machine: Machine = load(&assemble(source.as_str()).unwrap());
+  machine.map_device(0x8000, TIMER_LENGTH, Timer::new(TIMER_LINE)).unwrap();
+  machine.input_buffer.put_char('x');
+  machine.raise_interrupt(5);
+  assert_eq!(run(&mut machine), "TTT");
+  assert!(machine.interrupts.is_pending(5));
+  assert!(machine.input_buffer.has_bytes());
+}
+
+#[test]
+fn interrupts_wait_for_ei_and_reti_restores_the_flags() {
+  let source: &str = "
+    PUSHI :HANDLER
+    STOREI 0xfff4
+    EI
+    PRNI 'm'
+    HALT
+  :HANDLER
+    PRNI 'i'
+    RETI
+  ";
+  let mut machine: Machine = load(&assemble(source).unwrap());
+  assert!(machine.raise_interrupt(2));
+  assert!(!machine.raise_interrupt(8));
+  machine.step();
+  machine.step();
+  assert!(machine.interrupts.is_pending(2));
+  machine.flags.carry = true;
+
+  // The handler is entered straight after EI, with interrupts disabled
+  machine.step();
+  assert!(!machine.interrupts.is_pending(2));
+  assert!(!machine.flags.interrupt);
+  assert_eq!(machine.instruction_pointer_stack.depth(), 2);
+  machine.flags.carry = false;
+  assert_eq!(run(&mut machine), "im");
+  assert!(machine.flags.interrupt);
+  assert!(machine.flags.carry);
+  assert_eq!(machine.instruction_pointer_stack.depth(), 0);
+}
+
+#[test]
+fn instruction_table_looks_up_by_name_and_opcode() {
+  for instruction in INSTRUCTIONS.iter() {
+    assert_eq!(find_inst_by_opcode(&instruction.op_code).unwrap().inst, instruction.inst);
+    assert_eq!(find_inst_by_name(instruction.inst).unwrap().op_code, instruction.op_code);
+  }
+  assert_eq!(find_inst_by_name("halt").unwrap().op_code, 0x08);
+  assert!(find_inst_by_name("NOPE").is_none());
+  assert!(find_inst_by_opcode(&0xfe).is_none());
+}
+
+#[test]
+fn reports_assembler_diagnostics() {
+  let diagnostics: Vec<Diagnostic> = assemble("PUSHI :MISSING\nHALTT").unwrap_err();
+  let kinds: Vec<DiagnosticKind> = diagnostics.iter().map(|diagnostic| diagnostic.kind).collect();
+  assert!(kinds.contains(&DiagnosticKind::SyntaxError));
+  assert!(kinds.contains(&DiagnosticKind::UndefinedLabel));
+}
+
+#[test]
+fn reports_every_error_with_its_span() {
+  let mut diagnostics: Vec<Diagnostic> = assemble("PUSHI 0x0001\n  HALTT\nPUSHI :MISSING\nADD 5").unwrap_err();
+  diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+  let found: Vec<(DiagnosticKind, usize, (usize, usize))> =
+    diagnostics.iter().map(|diagnostic| (diagnostic.kind, diagnostic.line, diagnostic.span)).collect();
+  assert_eq!(
+    found,
+    vec![
+      (DiagnosticKind::SyntaxError, 2, (2, 7)),
+      (DiagnosticKind::UndefinedLabel, 3, (6, 14)),
+      (DiagnosticKind::SyntaxError, 4, (4, 5)),
+    ]
+  );
+  let rendered: String = diagnostics[0].to_string();
+  assert!(rendered.contains("2 |   HALTT\n  |   ^^^^^\n"), "{}", rendered);
+  assert!(rendered.contains("hint: did you mean \"HALT\"?"), "{}", rendered);
+}
+
+#[test]
+fn lists_addresses_bytes_and_source() {
+  let mut assembler: Assembler = Assembler::new();
+  assembler.add_string(".MACRO TWICE\nPRNI 0x41\nPRNI 0x41\n.ENDM\n:START\nTWICE\n.DATA 0x0010 \"Hi\"\nHALT");
+  assembler.assemble_object().unwrap();
+  // Expanded macro lines show the line in the body, marked with +
+  assert_eq!(
+    assembler.listing(),
+    "Addr  Bytes                     Line  Source\n\
+     \n\
+     File: <input>\n\
+     \x20                                  5  :START\n\
+     0000  f1 41                        2+ PRNI 0x41\n\
+     0002  f1 41                        3+ PRNI 0x41\n\
+     0010  48 69                        7  .DATA 0x0010 \"Hi\"\n\
+     0004  08                           8  HALT\n\
+     \n\
+     Symbols:\n\
+     \x20 0000  :START\n"
+  );
+}
+
+#[test]
+fn resolves_local_and_anonymous_labels() {
+  let source: &str = "
+    JMPI :FIRST.skip
+  :-
+    HALT
+  :FIRST
+  :.skip
+    PRNI 'a'
+    JMPI :SECOND
+  :SECOND
+    JMPI :.skip
+    PRNI 'x'
+  :.skip
+    PRNI 'b'
+    JMPI :++
+  :+
+    PRNI 'x'
+  :+
+    PRNI 'c'
+    JMPI :-
+  ";
+  let mut machine: Machine = load(&assemble(source).unwrap());
+  assert_eq!(run(&mut machine), "abc");
+
+  // Local labels belong to their routine, so another routine can't refer to them by the short name
+  let diagnostics: Vec<Diagnostic> = assemble(":FIRST\n:.loop\nHALT\n:SECOND\nJMPI :.loop").unwrap_err();
+  assert_eq!(diagnostics[0].kind, DiagnosticKind::UndefinedLabel);
+  assert_eq!(diagnostics[0].line, 5);
+}
+
+#[test]
+fn includes_files_through_search_paths() {
+  let mut assembler: Assembler = Assembler::new();
+  assembler.add_search_path(&example_path("lib"));
+  assembler.add_string(".INCLUDE \"print.jello\"\n.INCLUDE \"print.jello\"\nHALT");
+  assert!(assembler.assemble_object().is_ok());
+
+  let diagnostics: Vec<Diagnostic> = assemble("HALT\n.INCLUDE \"nowhere.jello\"").unwrap_err();
+  assert_eq!(diagnostics[0].kind, DiagnosticKind::IncludeError);
+  assert_eq!((diagnostics[0].line, diagnostics[0].span), (2, (9, 24)));
+  assert_eq!(diagnostics[0].message, "could not find included file \"nowhere.jello\"");
+}
+
+#[test]
+fn reports_files_which_include_themselves() {
+  let directory: PathBuf = std::env::temp_dir().join(format!("rusty_jello_include_cycle_{}", std::process::id()));
+  fs::create_dir_all(&directory).unwrap();
+  fs::write(directory.join("first.jello"), "NOOP\n.INCLUDE \"second.jello\"").unwrap();
+  fs::write(directory.join("second.jello"), ".INCLUDE \"first.jello\"\nHALT").unwrap();
+
+  let path: PathBuf = directory.join("first.jello");
+  let mut assembler: Assembler = Assembler::new();
+  assembler.add_file_string(&path, fs::read_to_string(&path).unwrap().as_str());
+  let result: Result<ObjectFile, Vec<Diagnostic>> = assembler.assemble_object();
+  fs::remove_dir_all(&directory).unwrap();
+
+  let diagnostics: Vec<Diagnostic> = result.unwrap_err();
+  assert_eq!(diagnostics.len(), 1);
+  assert_eq!(diagnostics[0].kind, DiagnosticKind::IncludeError);
+  assert!(diagnostics[0].file.as_ref().unwrap().ends_with("second.jello"));
+  assert!(diagnostics[0].message.ends_with("first.jello\" includes itself"), "{}", diagnostics[0].message);
+}
+
+/// Evaluates an expression which uses no constants or labels
+fn evaluate(text: &str) -> Result<i64, String> {
+  let parsed: Expression = expression::parse(text, &|name: &str| Err(format!("unknown constant \"{}\"", name)))?;
+  return parsed.evaluate(&|_: &str| None);
+}
+
+#[test]
+fn expressions_follow_precedence() {
+  assert_eq!(evaluate("2+3*4"), Ok(14));
+  assert_eq!(evaluate("(2+3)*4"), Ok(20));
+  assert_eq!(evaluate("10-4-3"), Ok(3));
+  assert_eq!(evaluate("7%4*2"), Ok(6));
+  assert_eq!(evaluate("0x10/0b10+1"), Ok(9));
+  assert_eq!(evaluate("-2*3"), Ok(-6));
+  assert_eq!(evaluate(">0x1234+1"), Ok(0x13));
+  assert_eq!(evaluate("<(0x1234+1)"), Ok(0x35));
+  assert_eq!(evaluate("'a'+1"), Ok(98));
+  assert!(evaluate("1/0").is_err());
+  assert!(evaluate("(1+2").is_err());
+  assert!(evaluate("0x10000").is_err());
+
+  // A constant is substituted as a whole, not as text
+  let pushi: u8 = find_inst_by_name("PUSHI").unwrap().op_code;
+  let object: ObjectFile = assemble(".EQU SIZE 2+3\nPUSHI SIZE*2").unwrap();
+  assert_eq!(object.to_raw(), vec![pushi, 10, 0]);
+}
+
+#[test]
+fn expressions_report_overflow() {
+  assert!(evaluate("0xffff*0xffff*0xffff*0xffff*0xffff").is_err());
+  assert!(evaluate("0x7fff*0xffff*0xffff*0xffff+0x7fff*0xffff*0xffff*0xffff").is_err());
+  // The most negative value there is, which can't be negated or divided by -1
+  let minimum: &str = "(0-0x8000*0x8000*0x8000*0x8000)*8";
+  assert_eq!(evaluate(minimum), Ok(i64::MIN));
+  assert!(evaluate(format!("-{}", minimum).as_str()).is_err());
+  assert!(evaluate(format!("{}/(0-1)", minimum).as_str()).is_err());
+  assert!(evaluate(format!("{}-1", minimum).as_str()).is_err());
+
+  let diagnostics: Vec<Diagnostic> = assemble("PUSHI 0xffff*0xffff*0xffff*0xffff*0xffff\nHALT").unwrap_err();
+  assert_eq!(diagnostics.len(), 1);
+  assert_eq!(diagnostics[0].kind, DiagnosticKind::ValueError);
+}
+
+#[test]
+fn data_directives_lay_out_bytes() {
+  let source: &str = "
+    .BYTE 1 'a' \"bc\" 0x0012 <0x1234 >0x1234
+    .WORD 0x1234 7 :END
+    .SPACE 2 0xee
+    .ALIGN 4
+    .ORG 0x0014
+  :END
+    .BYTE 0xff
+  ";
+  let object: ObjectFile = assemble(source).unwrap();
+  let expected: Vec<u8> = vec![
+    0x01, 0x61, 0x62, 0x63, 0x12, 0x34, 0x12, 0x34, 0x12, 0x07, 0x00, 0x14, 0x00, 0xee, 0xee, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xff,
+  ];
+  assert_eq!(object.to_raw(), expected);
+
+  // A string constant is still a string, but any other value has to fit in a byte
+  assert_eq!(assemble(".EQU S \"hi\"\n.BYTE S 0").unwrap().to_raw(), b"hi\0".to_vec());
+  for source in [
+    ".EQU W 0x1234\n.BYTE W",
+    ".BYTE 0x1234",
+    ".BYTE 300",
+    ".BYTE 0x12+0x1200",
+    ".BYTE :END\n:END\nHALT",
+    ".WORD \"abc\"",
+    ".ORG 0x10\n.ORG 0x08",
+    ".ALIGN 0",
+    ".SPACE 2 0x100",
+  ]
+  .iter()
+  {
+    let diagnostics: Vec<Diagnostic> = assemble(source).unwrap_err();
+    assert_eq!(diagnostics[0].kind, DiagnosticKind::DataError, "{}", source);
+  }
+
+  // Operands are separated by spaces, so a tab leaves a single word that isn't a directive
+  let diagnostics: Vec<Diagnostic> = assemble(".BYTE\t1\nHALT").unwrap_err();
+  assert_eq!(diagnostics[0].kind, DiagnosticKind::SyntaxError);
+  assert!(!diagnostics[0].hint.as_ref().is_some_and(|hint| hint.contains(".ALIGN")), "{:?}", diagnostics[0].hint);
+}
+
+#[test]
+fn data_and_code_must_fit_in_memory() {
+  let bytecode: Vec<u8> = assemble(".ORG 0xfffe\n.WORD 0x1234").unwrap().to_raw();
+  assert_eq!(bytecode.len(), 65536);
+  assert_eq!(bytecode[0xfffe..], [0x34, 0x12]);
+
+  for source in [".ORG 0xffff\n.WORD 0x1234", ".ORG 0xfffe\nPUSHI 0x1234\nHALT", "HALT\n.DATA 0xffff 0x1234"].iter() {
+    let diagnostics: Vec<Diagnostic> = assemble(source).unwrap_err();
+    assert_eq!(diagnostics.len(), 1, "{}", source);
+    assert_eq!(diagnostics[0].kind, DiagnosticKind::DataError);
+    assert_eq!(diagnostics[0].line, 2);
+    assert!(diagnostics[0].message.ends_with("run past the end of memory"), "{}", diagnostics[0].message);
+  }
+  let diagnostics: Vec<Diagnostic> = assemble(".ORG 0xffff\n.WORD 0x1234").unwrap_err();
+  assert_eq!(diagnostics[0].span, (0, 12));
+}
+
+#[test]
+fn macros_substitute_parameters_and_rename_labels() {
+  let definition: &str = "
+  .MACRO PRINT_TWICE CHAR
+  :AGAIN
+    PRNI CHAR
+    PRNI CHAR
+  .ENDM
+  ";
+  // Each expansion gets its own :AGAIN, which doesn't clash with a label written like a renamed one
+  let source: String = format!("{}\nPRINT_TWICE 'a'\nPRINT_TWICE 'b'\nJMPI :AGAIN__1\n:AGAIN__1\nHALT", definition);
+  let mut machine: Machine = load(&assemble(source.as_str()).unwrap());
+  assert_eq!(run(&mut machine), "aabb");
+
+  let source: String = format!("{}\nPRINT_TWICE\nHALT", definition);
+  let diagnostics: Vec<Diagnostic> = assemble(source.as_str()).unwrap_err();
+  assert_eq!(diagnostics[0].kind, DiagnosticKind::MacroError);
+  assert_eq!(diagnostics[0].line, 8);
+}
+
+#[test]
+fn macro_expansion_is_limited() {
+  // Each level invokes the one below it 4 times, so expanding M9 in full would take 4^9 expansions
+  let mut source: String = ".MACRO M0\nNOOP\n.ENDM\n".to_string();
+  for level in 1..10 {
+    source += format!(".MACRO M{}\n{}.ENDM\n", level, format!("M{}\n", level - 1).repeat(4)).as_str();
+  }
+  source += "M9\nHALT";
+  let diagnostics: Vec<Diagnostic> = assemble(source.as_str()).unwrap_err();
+  assert_eq!(diagnostics.len(), 1);
+  assert_eq!(diagnostics[0].kind, DiagnosticKind::MacroError);
+
+  let source: &str = ".MACRO SELF\nSELF\n.ENDM\nSELF";
+  assert_eq!(assemble(source).unwrap_err()[0].kind, DiagnosticKind::MacroError);
+}
+
+#[test]
+fn halts_on_an_illegal_instruction() {
+  let mut machine: Machine = load(&assemble("PRNI 'a'\n.BYTE 0xfe\nHALT").unwrap());
+  assert_eq!(run(&mut machine), "a");
+  let fault = machine.fault.unwrap();
+  assert_eq!(fault.kind, FaultKind::IllegalInstruction);
+  assert_eq!(fault.address, 2);
+  assert_eq!(fault.op_code, 0xfe);
+}
+
+#[test]
+fn stacks_hold_16_values() {
+  let mut stack: Stack = Stack::new();
+  for value in 0..16 {
+    assert!(stack.push(value));
+    assert_eq!(stack.take_fault(), None);
+  }
+  assert_eq!(stack.depth(), 16);
+  // The 17th push shifts the oldest value out
+  assert!(!stack.push(16));
+  assert_eq!(stack.take_fault(), Some(FaultKind::StackOverflow));
+  assert_eq!(stack.depth(), 16);
+  assert_eq!(stack.pop(), 16);
+  assert_eq!(stack.pop(), 15);
+
+  let mut source: String = "PUSHI 0x0001\n".repeat(16);
+  source += "PRNI 'x'\nPUSHI 0x0001\nPRNI 'y'\nHALT";
+  let mut machine: Machine = load(&assemble(source.as_str()).unwrap());
+  machine.set_fault_policy(FaultKind::StackOverflow, FaultPolicy::Halt);
+  assert_eq!(run(&mut machine), "x");
+  assert_eq!(machine.fault.unwrap().kind, FaultKind::StackOverflow);
+}
+
+#[test]
+fn ignores_stack_faults_unless_asked_to_halt() {
+  let mut machine: Machine = load(&assemble("PUSHI 0x0001\nDROP\nDROP\nPRNI 'x'\nHALT").unwrap());
+  assert_eq!(machine.fault_policy(FaultKind::StackUnderflow), FaultPolicy::Ignore);
+  assert_eq!(run(&mut machine), "x");
+  assert_eq!(machine.fault, None);
+
+  let mut machine: Machine = load(&assemble("PUSHI 0x0001\nDROP\nDROP\nPRNI 'x'\nHALT").unwrap());
+  machine.set_fault_policy(FaultKind::StackUnderflow, FaultPolicy::Halt);
+  assert_eq!(machine.fault_policy(FaultKind::StackOverflow), FaultPolicy::Ignore);
+  assert_eq!(run(&mut machine), "");
+  assert_eq!(machine.fault.unwrap().kind, FaultKind::StackUnderflow);
+}
+
+#[test]
+fn traps_divide_by_zero_to_a_handler() {
+  let source: &str = "
+    PUSHI 0x0000
+    PUSHI 0x0006
+    DIV
+    PRNI 'b'
+    HALT
+  :HANDLER
+    LR3
+    PUSH
+    DUMP8
+    RET
+  ";
+  let object: ObjectFile = assemble(source).unwrap();
+  let handler: u16 = object.symbols.iter().find(|symbol| symbol.name == "HANDLER").unwrap().address;
+
+  let mut machine: Machine = load(&object);
+  assert_eq!(run(&mut machine), "");
+  assert_eq!(machine.fault.unwrap().kind, FaultKind::DivideByZero);
+
+  let mut machine: Machine = load(&object);
+  machine.set_fault_policy(FaultKind::DivideByZero, FaultPolicy::Trap(handler));
+  assert_eq!(run(&mut machine), "0x04b");
+  assert_eq!(machine.fault, None);
+}
+
+#[test]
+fn links_objects_through_exports_and_imports() {
+  let main: ObjectFile = assemble(".IMPORT :GREET\nCALLI :GREET\nHALT").unwrap();
+  let library: ObjectFile = assemble(".EXPORT :GREET\n:GREET\nPRNI 'h'\nPRNI 'i'\nRET").unwrap();
+
+  let mut linker: Linker = Linker::new();
+  linker.add_object("main", main, None);
+  linker.add_library("greet", library);
+  let linked: ObjectFile = linker.link().unwrap();
+
+  let reloaded: ObjectFile = ObjectFile::from_bytes(&linked.to_bytes()).unwrap();
+  let mut machine: Machine = load(&reloaded);
+  assert_eq!(run(&mut machine), "hi");
+}
+
+#[test]
+fn links_objects_at_a_relocated_address() {
+  let object: ObjectFile = assemble("JMPI :END\nPRNI 'x'\n:END\nPRNI 'y'\nHALT").unwrap();
+  let mut linker: Linker = Linker::new();
+  linker.add_object("main", object, Some(0x0100));
+  let linked: ObjectFile = linker.link().unwrap();
+
+  let mut machine: Machine = load(&linked);
+  assert_eq!(machine.instruction_pointer, 0x0100);
+  assert_eq!(machine.memory[0x0101..0x0103], [0x05, 0x01]);
+  assert_eq!(run(&mut machine), "y");
+}
+
+#[test]
+fn reports_link_errors() {
+  let mut linker: Linker = Linker::new();
+  linker.add_object("main", assemble(".IMPORT :GREET\nCALLI :GREET\nHALT").unwrap(), None);
+  linker.add_library("unused", assemble(".EXPORT :OTHER\n:OTHER\nRET").unwrap());
+  assert_eq!(
+    linker.link().err(),
+    Some(vec!["Link Error: undefined symbol \"GREET\" imported by main".to_string()])
+  );
+
+  let mut linker: Linker = Linker::new();
+  linker.add_object("first", assemble("NOOP\nNOOP\nHALT").unwrap(), Some(0x0010));
+  linker.add_object("second", assemble("HALT").unwrap(), Some(0x0011));
+  let errors: Vec<String> = linker.link().unwrap_err();
+  assert_eq!(errors, vec!["Link Error: second (0x0011-0x0011) overlaps first (0x0010-0x0012)".to_string()]);
+}
+
+#[test]
+fn object_files_keep_source_lines() {
+  let object: ObjectFile = assemble_example("word-wrap.jello");
+  let reloaded: ObjectFile = ObjectFile::from_bytes(&object.to_bytes()).unwrap();
+  assert_eq!(reloaded.files, object.files);
+  let lines = |object: &ObjectFile| -> Vec<(u16, u16, u32)> {
+    return object.lines.iter().map(|entry| (entry.address, entry.file, entry.line)).collect();
+  };
+  assert_eq!(lines(&reloaded), lines(&object));
+
+  let source_map: SourceMap = SourceMap::new(&reloaded);
+  let print: u16 = source_map.symbol_address("PRINT").unwrap();
+  let description: String = source_map.describe(print);
+  assert!(description.ends_with("lib/print.jello:43 in :PRINT"), "{}", description);
+
+  // Version 2 objects have a line section without files, which is read as one unnamed file
+  let mut version_2: Vec<u8> = b"JELO\x02\x00\x00\x00\x00\x00\x02\x00".to_vec();
+  version_2.extend_from_slice(b"\x01\x03\x00\x00\x00\x00\x00\x08");
+  version_2.extend_from_slice(b"\x03\x06\x00\x00\x00\x00\x00\x05\x00\x00\x00");
+  let source_map: SourceMap = SourceMap::new(&ObjectFile::from_bytes(&version_2).unwrap());
+  assert_eq!(source_map.line_at(0), Some(("", 5)));
+  assert_eq!(source_map.describe(0), "line 5");
+}
+
+#[test]
+fn object_files_reject_malformed_input() {
+  let bytes: Vec<u8> = assemble_example("fib.jello").to_bytes();
+  assert!(ObjectFile::from_bytes(&bytes).is_ok());
+
+  let mut bad_magic: Vec<u8> = bytes.clone();
+  bad_magic[0] = b'X';
+  assert_eq!(
+    ObjectFile::from_bytes(&bad_magic).err(),
+    Some("Object Format Error: missing magic number".to_string())
+  );
+
+  let mut newer_version: Vec<u8> = bytes.clone();
+  newer_version[4] = 0xff;
+  let error: String = ObjectFile::from_bytes(&newer_version).err().unwrap();
+  assert!(error.contains("format version 255 is newer"), "{}", error);
+
+  for length in 0..bytes.len() {
+    assert!(ObjectFile::from_bytes(&bytes[..length]).is_err(), "accepted the first {} bytes", length);
+  }
+}
+
+#[test]
+fn disassembly_reassembles_to_the_same_bytes() {
+  let bytecode: Vec<u8> = assemble_example("fib.jello").to_raw();
+  let mut disassembler: Disassembler = Disassembler::new();
+  disassembler.add_bytes(&bytecode);
+  let object: ObjectFile = assemble(disassembler.disassemble().as_str()).unwrap();
+  assert_eq!(object.to_raw(), bytecode);
+}
+
+#[test]
+fn disassembly_labels_jump_targets_and_keeps_trailing_data() {
+  let source: &str = "JMPI :END\nPRNI 0x78\n:END\nHALT\n.DATA 0x0006 0xfe\n.DATA 0x0010 \"Hi\\n\"";
+  let bytecode: Vec<u8> = assemble(source).unwrap().to_raw();
+  let mut disassembler: Disassembler = Disassembler::new();
+  disassembler.add_bytes(&bytecode);
+  let disassembly: String = disassembler.disassemble();
+  // Decoding stops at the first byte which isn't an opcode, everything after it is data
+  assert_eq!(
+    disassembly,
+    "#Disassembled by Rusty Jello\nJMPI :L0005\nPRNI 0x78\n:L0005\nHALT\n.DATA 0x0006 0xfe\n.DATA 0x0010 \"Hi\\n\"\n"
+  );
+  assert_eq!(assemble(disassembly.as_str()).unwrap().to_raw(), bytecode);
+}